@@ -0,0 +1,158 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// Tracking of the apps launched on behalf of the Host.
+//
+
+use std::collections::HashSet;
+use std::fs;
+use std::process::Child;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::event_channel::EventSender;
+use crate::message::Message;
+
+/// Returns the fields of /proc/<pid>/stat following the command name, so
+/// the first one is the state of the process.
+pub fn stat_fields(pid: u32) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name is enclosed in parentheses and may contain spaces,
+    // so skip it before splitting the rest of the fields.
+    let fields = &stat[stat.rfind(')')? + 1..];
+    Some(fields.split_whitespace().map(|f| f.to_string()).collect())
+}
+
+fn parent_pid(pid: u32) -> Option<u32> {
+    stat_fields(pid)?.get(1)?.parse().ok()
+}
+
+/// Returns the pid of the process and all of its descendants, parents
+/// always going before their children.
+pub fn process_tree(pid: u32) -> Vec<u32> {
+    let mut parents: Vec<(u32, u32)> = Vec::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let p = match entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                Some(p) => p,
+                None => continue,
+            };
+            if let Some(ppid) = parent_pid(p) {
+                parents.push((p, ppid));
+            }
+        }
+    }
+
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(
+            parents
+                .iter()
+                .filter(|(_, ppid)| *ppid == parent)
+                .map(|(p, _)| *p),
+        );
+        i += 1;
+    }
+    tree
+}
+
+#[derive(Default)]
+struct Tracked {
+    pids: Mutex<HashSet<u32>>,
+    // Notified each time an app exits.
+    exited: Condvar,
+}
+
+/// The apps still running. Cloning it returns a handle to the same set.
+#[derive(Clone, Default)]
+pub struct Apps {
+    tracked: Arc<Tracked>,
+}
+
+impl Apps {
+    pub fn new() -> Apps {
+        Apps::default()
+    }
+
+    /// Starts tracking the app, sending its exit code once it finishes.
+    pub fn track(&self, mut child: Child, sender: EventSender<Message>) {
+        let pid = child.id();
+        self.tracked.pids.lock().unwrap().insert(pid);
+
+        let tracked = self.tracked.clone();
+        thread::spawn(move || {
+            let exit_code = match child.wait() {
+                Ok(exit_status) => match exit_status.code() {
+                    Some(code) => code,
+                    None => -1,
+                },
+                Err(_) => -1,
+            };
+
+            tracked.pids.lock().unwrap().remove(&pid);
+            tracked.exited.notify_all();
+            sender.send(Message::AppExit(exit_code)).unwrap();
+        });
+    }
+
+    pub fn pids(&self) -> Vec<u32> {
+        self.tracked.pids.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracked.pids.lock().unwrap().is_empty()
+    }
+
+    /// Asks the apps to quit, sending SIGTERM to every process they've
+    /// created. The ones deeper in the tree go first, so they get the
+    /// chance to exit cleanly before their parents (i.e. flatpak and
+    /// bwrap) go away and take them down.
+    pub fn terminate(&self) {
+        for pid in self.pids() {
+            for p in process_tree(pid).iter().rev() {
+                debug!("sending SIGTERM to {}", p);
+                // Safe because kill() doesn't touch our memory.
+                unsafe { libc::kill(*p as libc::pid_t, libc::SIGTERM) };
+            }
+        }
+    }
+
+    /// Waits for all apps to exit, up to the given timeout. Returns
+    /// whether they did.
+    pub fn wait_all(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut pids = self.tracked.pids.lock().unwrap();
+        while !pids.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            pids = self
+                .tracked
+                .exited
+                .wait_timeout(pids, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+}
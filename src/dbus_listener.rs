@@ -14,146 +14,756 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use crate::event_channel::{EventReceiver, EventSender};
+use crate::icon;
 use crate::message::Message;
+use crate::notification_filter::*;
+use crate::portal::{self, Portal};
+use crate::reactor::Source;
+use crate::screensaver::{self, ScreenSaver};
+use crate::status_notifier::{self, StatusNotifierWatcher};
 use dbus::arg::{RefArg, Variant};
 use dbus::tree;
-use dbus::{BusType, Connection, Path, SignalArgs};
-use flatkvm_qemu::dbus_codegen::*;
-use flatkvm_qemu::dbus_notifications::{DbusNotification, DbusNotificationClosed};
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::{Receiver, Sender};
+use dbus::{
+    BusType, Connection, ConnectionItem, Path, RequestNameReply, SignalArgs, WatchEvent,
+    DBUS_NAME_FLAG_REPLACE_EXISTING,
+};
+use flatkvm_qemu::dbus_codegen::{
+    OrgFreedesktopNotificationsActionInvoked, OrgFreedesktopNotificationsNotificationClosed,
+};
+use flatkvm_qemu::dbus_notifications::{
+    DbusNotification, DbusNotificationAction, DbusNotificationClose, DbusNotificationClosed,
+    DbusNotificationStatus,
+};
+use flatkvm_qemu::status_notifier::StatusNotifierActivation;
+use log::{debug, error};
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Duration;
-
-static mut DBUS_SENDER: Option<Mutex<Sender<Message>>> = None;
-static DBUS_NOTIFICATION_ID: AtomicUsize = AtomicUsize::new(5);
-
-#[derive(Copy, Clone, Default, Debug)]
-struct TData;
-impl tree::DataType for TData {
-    type Tree = ();
-    type ObjectPath = Arc<Notification>;
-    type Property = ();
-    type Interface = ();
-    type Method = ();
-    type Signal = ();
-}
-
-#[derive(Copy, Clone, Default, Debug)]
-struct Notification;
-impl OrgFreedesktopNotifications for Notification {
-    type Err = dbus::tree::MethodErr;
-    fn close_notification(&self, _id: u32) -> Result<(), Self::Err> {
-        Ok(())
+use std::time::{Duration, Instant};
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+// How long to wait before trying to connect again to the session bus.
+const BUS_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+const DBUS_NAME: &str = "org.freedesktop.DBus";
+/// Match rule for the NameOwnerChanged signals emitted by the bus.
+pub const NAME_OWNER_CHANGED_RULE: &str = "type='signal',sender='org.freedesktop.DBus',\
+                                           interface='org.freedesktop.DBus',\
+                                           member='NameOwnerChanged'";
+
+// Capabilities the agent knows how to relay. The ones advertised to the apps
+// are the subset of these also supported by the Host.
+const AGENT_CAPABILITIES: [&str; 4] = ["actions", "body", "body-markup", "icon-static"];
+// Version of the Desktop Notifications Specification we implement.
+const SPEC_VERSION: &str = "1.2";
+
+// Reason code for notifications closed by a call to CloseNotification.
+const CLOSED_BY_CALL: u32 = 3;
+// Urgency level assumed when the "urgency" hint is missing or invalid.
+const URGENCY_NORMAL: u8 = 1;
+
+// Highest urgency level defined by the specification.
+const URGENCY_CRITICAL: u8 = 2;
+
+/// Sends messages to the Host from the D-Bus services, whose method
+/// handlers may only hold shared references to them.
+#[derive(Debug)]
+pub struct HostSender(Mutex<EventSender<Message>>);
+
+impl HostSender {
+    pub fn new(sender: EventSender<Message>) -> HostSender {
+        HostSender(Mutex::new(sender))
+    }
+
+    pub fn send(&self, msg: Message) {
+        self.0.lock().unwrap().send(msg).unwrap();
     }
+}
+
+/// Requests the well-known name of one of our services, replacing its
+/// current owner if it allows so, and serves the tree implementing it.
+/// Returns whether we got the name. If not, we're queued and will get a
+/// NameAcquired signal once the owner goes away.
+pub fn serve(
+    c: &Connection,
+    name: &str,
+    tree: tree::Tree<tree::MTFn<()>, ()>,
+) -> Result<bool, String> {
+    let owned = match c
+        .register_name(name, DBUS_NAME_FLAG_REPLACE_EXISTING)
+        .map_err(|err| err.to_string())?
+    {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => true,
+        RequestNameReply::InQueue | RequestNameReply::Exists => {
+            error!("{} owned by another service, queued", name);
+            false
+        }
+    };
+
+    tree.set_registered(c, true)
+        .map_err(|err| err.to_string())?;
+    c.add_handler(tree);
 
-    fn get_capabilities(&self) -> Result<Vec<String>, Self::Err> {
-        let capabilities: Vec<String> = vec![
-            "actions".to_string(),
-            "body".to_string(),
-            "body-hyperlinks".to_string(),
-            "body-markup".to_string(),
-            "icon-static".to_string(),
-            "sound".to_string(),
-            "persistence".to_string(),
-            "action-icons".to_string(),
-        ];
+    Ok(owned)
+}
+
+/// Events coming from the Host that must be emitted as D-Bus signals.
+pub enum DbusSignal {
+    NotificationClosed(DbusNotificationClosed),
+    ActionInvoked(DbusNotificationAction),
+    Portal(portal::PortalEvent),
+    StatusNotifier(StatusNotifierActivation),
+}
 
-        Ok(capabilities)
+/// If the message is a NameOwnerChanged signal telling that a name has
+/// lost its owner, returns that name. Any client may broadcast a signal
+/// looking like this one, so only those sent by the bus itself count.
+pub fn name_lost(msg: &dbus::Message) -> Option<String> {
+    let (_, _, iface, member) = msg.headers();
+    if msg.sender().map_or(true, |s| &*s != DBUS_NAME)
+        || iface.as_ref().map(|i| i.as_str()) != Some(DBUS_NAME)
+        || member.as_ref().map(|m| m.as_str()) != Some("NameOwnerChanged")
+    {
+        return None;
     }
 
-    fn get_server_information(&self) -> Result<(String, String, String, String), Self::Err> {
-        Ok((
-            "dummy".to_string(),
-            "dummy".to_string(),
-            "dummy".to_string(),
-            "dummy".to_string(),
-        ))
+    match msg.get3::<&str, &str, &str>() {
+        (Some(name), _, Some("")) => Some(name.to_string()),
+        _ => None,
     }
+}
 
-    fn notify(
-        &self,
-        app_name: &str,
-        replaces_id: u32,
-        app_icon: &str,
-        summary: &str,
-        body: &str,
-        _actions: Vec<&str>,
-        _hints: HashMap<&str, Variant<Box<RefArg>>>,
-        expire_timeout: i32,
-    ) -> Result<u32, Self::Err> {
-        println!(
-            "notification: app_name={}, replaces_id={}, app_icon={}, summary={}, body={}",
-            app_name, replaces_id, app_icon, summary, body
-        );
+type Hints<'a> = HashMap<&'a str, Variant<Box<dyn RefArg>>>;
+
+fn hint_str(hints: &Hints, name: &str) -> Option<String> {
+    hints
+        .get(name)
+        .and_then(|v| v.0.as_str())
+        .filter(|s| !s.is_empty())
+        .map(|s| sanitize_text(s, MAX_APP_NAME_LEN))
+}
+
+fn hint_urgency(hints: &Hints) -> u8 {
+    match hints.get("urgency").and_then(|v| v.0.as_u64()) {
+        Some(urgency) if urgency <= URGENCY_CRITICAL as u64 => urgency as u8,
+        _ => URGENCY_NORMAL,
+    }
+}
+
+// Returns the id following "last", wrapping around without ever returning
+// 0 (which means "no id" in replaces_id) or an id that's still in use.
+fn next_id<F: Fn(u32) -> bool>(last: &mut u32, in_use: F) -> u32 {
+    loop {
+        *last = last.wrapping_add(1);
+        if *last != 0 && !in_use(*last) {
+            return *last;
+        }
+    }
+}
+
+// A notification still being shown.
+#[derive(Debug)]
+struct Shown {
+    host_id: u32,
+    // Unique bus name of the app that sent it.
+    owner: String,
+}
+
+#[derive(Default, Debug)]
+struct NotificationIds {
+    last_guest_id: u32,
+    last_host_id: u32,
+    // Maps the ids handed out to the guest apps to the ones used in the
+    // messages exchanged with the Host, for every notification still shown.
+    shown: HashMap<u32, Shown>,
+}
 
-        let nid = DBUS_NOTIFICATION_ID.fetch_add(1, Ordering::SeqCst);
-        // Safe because the Option is only changed in handle_dbus_notifications,
-        // and the Sender is protected by a Mutex.
-        unsafe {
-            if let Some(sender_mutex) = &DBUS_SENDER {
-                let sender = sender_mutex.lock().unwrap();
-                sender
-                    .send(Message::DbusNotification(DbusNotification {
-                        id: nid as u32,
-                        summary: summary.to_string(),
-                        body: body.to_string(),
-                        expire_timeout,
-                    }))
-                    .unwrap();
+impl NotificationIds {
+    // Returns the guest and host ids for a new notification from "owner",
+    // and the host id it replaces (0 if none). If replaces_id refers to a
+    // notification from the same owner that's still being shown, its ids
+    // are reused so the Host updates it in place. Apps can't replace the
+    // notifications of others.
+    fn assign(&mut self, owner: &str, replaces_id: u32) -> (u32, u32, u32) {
+        if let Some(shown) = self.shown.get(&replaces_id) {
+            if shown.owner == owner {
+                return (replaces_id, shown.host_id, shown.host_id);
             }
         }
 
-        Ok(nid as u32)
+        let shown = &self.shown;
+        let guest_id = next_id(&mut self.last_guest_id, |id| shown.contains_key(&id));
+        let host_id = next_id(&mut self.last_host_id, |id| {
+            shown.values().any(|s| s.host_id == id)
+        });
+        self.shown.insert(
+            guest_id,
+            Shown {
+                host_id,
+                owner: owner.to_string(),
+            },
+        );
+
+        (guest_id, host_id, 0)
     }
+
+    fn host_id(&self, guest_id: u32) -> Option<u32> {
+        self.shown.get(&guest_id).map(|s| s.host_id)
+    }
+
+    fn guest_id(&self, host_id: u32) -> Option<u32> {
+        self.shown
+            .iter()
+            .find(|(_, s)| s.host_id == host_id)
+            .map(|(&g, _)| g)
+    }
+
+    fn remove(&mut self, host_id: u32) -> Option<u32> {
+        let guest_id = self.guest_id(host_id)?;
+        self.shown.remove(&guest_id);
+        Some(guest_id)
+    }
+}
+
+// Arguments of a Notify call.
+struct NotifyArgs<'a> {
+    app_name: &'a str,
+    replaces_id: u32,
+    app_icon: &'a str,
+    summary: &'a str,
+    body: &'a str,
+    actions: Vec<&'a str>,
+    hints: Hints<'a>,
+    expire_timeout: i32,
 }
 
-fn dbus_create_iface() -> tree::Interface<tree::MTFn<TData>, TData> {
-    let f = tree::Factory::new_fn();
-    org_freedesktop_notifications_server(&f, (), |m| {
-        let a: &Arc<Notification> = m.path.get_data();
-        let b: &Notification = &a;
-        b
-    })
+impl<'a> NotifyArgs<'a> {
+    fn read(msg: &'a dbus::Message) -> Result<NotifyArgs<'a>, dbus::arg::TypeMismatchError> {
+        let mut i = msg.iter_init();
+        Ok(NotifyArgs {
+            app_name: i.read()?,
+            replaces_id: i.read()?,
+            app_icon: i.read()?,
+            summary: i.read()?,
+            body: i.read()?,
+            actions: i.read()?,
+            hints: i.read()?,
+            expire_timeout: i.read()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Notification {
+    sender: HostSender,
+    capabilities: Vec<String>,
+    ids: Mutex<NotificationIds>,
+    limiter: Mutex<RateLimiter>,
+    // Notifications the guest apps asked us to close, waiting for the
+    // Host to confirm it has removed them.
+    closing: Mutex<HashSet<u32>>,
 }
 
-pub fn handle_dbus_notifications(
-    sender: Sender<Message>,
-    receiver: Receiver<DbusNotificationClosed>,
-) {
-    unsafe {
-        DBUS_SENDER = Some(Mutex::new(sender));
+impl Notification {
+    fn new(sender: EventSender<Message>, capabilities: Vec<String>) -> Notification {
+        Notification {
+            sender: HostSender::new(sender),
+            capabilities,
+            ids: Mutex::new(NotificationIds::default()),
+            limiter: Mutex::new(RateLimiter::default()),
+            closing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Lets the Host know whether we're able to relay notifications.
+    fn report_status(&self, available: bool, reason: &str) {
+        if !available {
+            error!("notifications unavailable: {}", reason);
+        }
+        self.sender
+            .send(Message::DbusNotificationStatus(DbusNotificationStatus {
+                available,
+                reason: reason.to_string(),
+            }));
+    }
+
+    // Returns the signal telling the app the notification has been closed,
+    // if it's still known.
+    fn closed_message(
+        &self,
+        nc: DbusNotificationClosed,
+        path: &Path<'static>,
+    ) -> Option<dbus::Message> {
+        let id = self.ids.lock().unwrap().remove(nc.id)?;
+        let reason = if self.closing.lock().unwrap().remove(&id) {
+            CLOSED_BY_CALL
+        } else {
+            nc.reason
+        };
+        Some(OrgFreedesktopNotificationsNotificationClosed { id, reason }.to_emit_message(path))
     }
 
-    let notification = Notification;
+    // Returns the signal telling the app the user has invoked one of the
+    // actions of the notification, if it's still known.
+    fn action_message(
+        &self,
+        na: DbusNotificationAction,
+        path: &Path<'static>,
+    ) -> Option<dbus::Message> {
+        let id = self.ids.lock().unwrap().guest_id(na.id)?;
+        Some(
+            OrgFreedesktopNotificationsActionInvoked {
+                id,
+                action_key: na.action_key,
+            }
+            .to_emit_message(path),
+        )
+    }
 
-    let f = tree::Factory::new_fn();
-    let iface = dbus_create_iface();
+    fn close_notification(&self, id: u32) -> Result<(), String> {
+        let host_id = match self.ids.lock().unwrap().host_id(id) {
+            Some(host_id) => host_id,
+            None => return Err("unknown notification id".to_string()),
+        };
 
-    let mut tree = f.tree(());
-    tree = tree.add(
-        f.object_path("/org/freedesktop/Notifications", Arc::new(notification))
+        self.closing.lock().unwrap().insert(id);
+        self.sender
+            .send(Message::DbusNotificationClose(DbusNotificationClose {
+                id: host_id,
+            }));
+        Ok(())
+    }
+
+    // Relays the notification sent by "caller" to the Host, returning the
+    // id the app will know it by.
+    fn notify(&self, caller: &str, args: NotifyArgs) -> Result<u32, String> {
+        debug!(
+            "notification from {}: replaces_id={}",
+            caller, args.replaces_id
+        );
+
+        if !self.limiter.lock().unwrap().allow(caller) {
+            debug!("dropping notification from {}: rate limit exceeded", caller);
+            return Err("rate limit exceeded".to_string());
+        }
+
+        let icon = icon::notification_icon(args.app_icon, &args.hints);
+
+        let (guest_id, host_id, replaced_host_id) =
+            self.ids.lock().unwrap().assign(caller, args.replaces_id);
+        self.sender
+            .send(Message::DbusNotification(DbusNotification {
+                id: host_id,
+                replaces_id: replaced_host_id,
+                app_name: sanitize_text(args.app_name, MAX_APP_NAME_LEN),
+                desktop_entry: hint_str(&args.hints, "desktop-entry"),
+                category: hint_str(&args.hints, "category"),
+                urgency: hint_urgency(&args.hints),
+                summary: sanitize_text(args.summary, MAX_SUMMARY_LEN),
+                body: sanitize_markup(args.body, MAX_BODY_LEN),
+                actions: sanitize_actions(&args.actions),
+                icon,
+                expire_timeout: args.expire_timeout,
+            }));
+
+        Ok(guest_id)
+    }
+}
+
+fn create_tree(notification: Arc<Notification>) -> tree::Tree<tree::MTFn<()>, ()> {
+    let f = tree::Factory::new_fn::<()>();
+
+    let n = notification.clone();
+    let notify = f
+        .method("Notify", (), move |m| {
+            let args = NotifyArgs::read(m.msg)?;
+            let caller = m.msg.sender().map_or(String::new(), |s| s.to_string());
+            let id = n
+                .notify(&caller, args)
+                .map_err(|err| tree::MethodErr::failed(&err))?;
+            Ok(vec![m.msg.method_return().append1(id)])
+        })
+        .inarg::<&str, _>("app_name")
+        .inarg::<u32, _>("replaces_id")
+        .inarg::<&str, _>("app_icon")
+        .inarg::<&str, _>("summary")
+        .inarg::<&str, _>("body")
+        .inarg::<Vec<&str>, _>("actions")
+        .inarg::<Hints, _>("hints")
+        .inarg::<i32, _>("expire_timeout")
+        .outarg::<u32, _>("id");
+
+    let n = notification.clone();
+    let close_notification = f
+        .method("CloseNotification", (), move |m| {
+            let id: u32 = m.msg.read1()?;
+            n.close_notification(id)
+                .map_err(|err| tree::MethodErr::failed(&err))?;
+            Ok(vec![m.msg.method_return()])
+        })
+        .inarg::<u32, _>("id");
+
+    let n = notification.clone();
+    let get_capabilities = f
+        .method("GetCapabilities", (), move |m| {
+            Ok(vec![m.msg.method_return().append1(n.capabilities.clone())])
+        })
+        .outarg::<Vec<&str>, _>("capabilities");
+
+    let get_server_information = f
+        .method("GetServerInformation", (), |m| {
+            Ok(vec![m
+                .msg
+                .method_return()
+                .append2(env!("CARGO_PKG_NAME"), "flatkvm")
+                .append2(env!("CARGO_PKG_VERSION"), SPEC_VERSION)])
+        })
+        .outarg::<&str, _>("name")
+        .outarg::<&str, _>("vendor")
+        .outarg::<&str, _>("version")
+        .outarg::<&str, _>("spec_version");
+
+    let iface = f
+        .interface(NOTIFICATIONS_NAME, ())
+        .add_m(notify)
+        .add_m(close_notification)
+        .add_m(get_capabilities)
+        .add_m(get_server_information)
+        .add_s(
+            f.signal("NotificationClosed", ())
+                .sarg::<u32, _>("id")
+                .sarg::<u32, _>("reason"),
+        )
+        .add_s(
+            f.signal("ActionInvoked", ())
+                .sarg::<u32, _>("id")
+                .sarg::<&str, _>("action_key"),
+        );
+
+    f.tree(()).add(
+        f.object_path(NOTIFICATIONS_PATH, ())
             .introspectable()
             .add(iface),
-    );
+    )
+}
 
-    let c = Connection::get_private(BusType::Session).unwrap();
-    c.register_name("org.freedesktop.Notifications", 0).unwrap();
-    tree.set_registered(&c, true).unwrap();
+// Services we provide on the session bus, besides notifications.
+struct Services {
+    portal: Arc<Portal>,
+    watcher: Arc<StatusNotifierWatcher>,
+    screensaver: Arc<ScreenSaver>,
+}
 
-    c.add_handler(tree);
-    loop {
-        c.iter(500).next();
-        if let Ok(nc) = receiver.recv_timeout(Duration::new(0, 0)) {
-            let path: Path<'static> = format!("/org/freedesktop/Notifications").into();
-            let sig = OrgFreedesktopNotificationsNotificationClosed {
-                id: nc.id,
-                reason: nc.reason,
+// Registers our services on the bus.
+fn register(
+    c: &Connection,
+    notification: &Arc<Notification>,
+    services: &Services,
+) -> Result<(), String> {
+    // If another notification daemon doesn't let us take over its name,
+    // we'll get it once that one goes away.
+    if serve(c, NOTIFICATIONS_NAME, create_tree(notification.clone()))? {
+        notification.report_status(true, "");
+    } else {
+        notification.report_status(false, "name owned by another notification daemon");
+    }
+
+    portal::register(c, services.portal.clone())?;
+    status_notifier::register(c, services.watcher.clone())?;
+    screensaver::register(c, services.screensaver.clone())?;
+
+    Ok(())
+}
+
+/// Serves our services on the session bus, emitting the signals for the
+/// events coming from the Host. Connects again if the bus goes away.
+pub struct DbusListener {
+    notification: Arc<Notification>,
+    services: Services,
+    receiver: EventReceiver<DbusSignal>,
+    conn: Option<Connection>,
+    path: Path<'static>,
+    retry_at: Option<Instant>,
+    // Whether we've already told the Host the session bus is unavailable.
+    reported: bool,
+}
+
+impl DbusListener {
+    pub fn new(
+        sender: EventSender<Message>,
+        receiver: EventReceiver<DbusSignal>,
+        host_capabilities: Vec<String>,
+    ) -> DbusListener {
+        let capabilities = AGENT_CAPABILITIES
+            .iter()
+            .filter(|c| host_capabilities.iter().any(|h| h == **c))
+            .map(|c| c.to_string())
+            .collect();
+        debug!("notification capabilities: {:?}", capabilities);
+
+        let services = Services {
+            portal: Arc::new(Portal::new(sender.clone())),
+            watcher: Arc::new(StatusNotifierWatcher::new(sender.clone())),
+            screensaver: Arc::new(ScreenSaver::new(sender.clone())),
+        };
+
+        DbusListener {
+            notification: Arc::new(Notification::new(sender, capabilities)),
+            services,
+            receiver,
+            conn: None,
+            path: NOTIFICATIONS_PATH.into(),
+            retry_at: None,
+            reported: false,
+        }
+    }
+
+    pub fn portal(&self) -> Arc<Portal> {
+        self.services.portal.clone()
+    }
+
+    /// Connects to the session bus. If it's not available yet, it will be
+    /// retried later from the reactor.
+    pub fn connect(&mut self) {
+        self.retry_at = Some(Instant::now() + BUS_RETRY_INTERVAL);
+
+        let c = match Connection::get_private(BusType::Session) {
+            Ok(c) => c,
+            Err(err) => {
+                if !self.reported {
+                    self.notification
+                        .report_status(false, "session bus not available");
+                    self.reported = true;
+                }
+                debug!("can't connect to the session bus: {}", err.to_string());
+                return;
+            }
+        };
+
+        if let Err(err) = register(&c, &self.notification, &self.services) {
+            error!("error registering D-Bus services: {}", err);
+            return;
+        }
+
+        self.conn = Some(c);
+        self.retry_at = None;
+        self.reported = false;
+    }
+
+    fn disconnect(&mut self, err: &str) {
+        error!("error serving D-Bus requests: {}", err);
+        self.notification
+            .report_status(false, "session bus connection lost");
+        self.reported = true;
+        self.conn = None;
+        // The apps will have to register their items and inhibitions again
+        // once we're back, so drop those we're holding on the Host.
+        self.services.screensaver.release_all();
+        self.services.watcher.remove_all();
+        self.retry_at = Some(Instant::now() + BUS_RETRY_INTERVAL);
+    }
+
+    // Dispatches the activity on one of the connection watches.
+    fn handle_watch(&self, c: &Connection, pfd: &libc::pollfd) -> Result<(), String> {
+        let mut flags = 0;
+        if pfd.revents & libc::POLLIN != 0 {
+            flags |= WatchEvent::Readable as libc::c_uint;
+        }
+        if pfd.revents & libc::POLLOUT != 0 {
+            flags |= WatchEvent::Writable as libc::c_uint;
+        }
+        if pfd.revents & libc::POLLERR != 0 {
+            flags |= WatchEvent::Error as libc::c_uint;
+        }
+        if pfd.revents & libc::POLLHUP != 0 {
+            flags |= WatchEvent::Hangup as libc::c_uint;
+        }
+
+        // Method calls are dispatched to the tree, we only need to care
+        // about the signals telling us about the name ownership, and the
+        // replies to the calls sent by the services.
+        for item in c.watch_handle(pfd.fd, flags) {
+            if let ConnectionItem::MethodReturn(msg) = item {
+                self.services.watcher.handle_reply(c, &msg);
+            } else if let ConnectionItem::Signal(msg) = item {
+                self.services.screensaver.handle_signal(&msg);
+                for signal in self.services.watcher.handle_signal(&msg) {
+                    c.send(signal)
+                        .map_err(|_| "sending DBus signal failed".to_string())?;
+                }
+                if msg.get1::<&str>() != Some(NOTIFICATIONS_NAME) {
+                    continue;
+                }
+                match msg.headers().3.as_ref().map(|m| m.as_str()) {
+                    Some("NameAcquired") => self.notification.report_status(true, ""),
+                    Some("NameLost") => self
+                        .notification
+                        .report_status(false, "name taken by another notification daemon"),
+                    _ => (),
+                }
+            }
+        }
+
+        if !c.is_connected() {
+            return Err("disconnected from the session bus".to_string());
+        }
+
+        self.services.watcher.refresh(c);
+        Ok(())
+    }
+
+    // Emits the signals for the events coming from the Host. If we're not
+    // connected, they're dropped, as the apps they refer to are gone anyway.
+    fn handle_signals(&self) -> Result<(), String> {
+        for signal in self.receiver.try_iter() {
+            let c = match &self.conn {
+                Some(c) => c,
+                None => continue,
+            };
+            let msg = match signal {
+                DbusSignal::NotificationClosed(nc) => {
+                    self.notification.closed_message(nc, &self.path)
+                }
+                DbusSignal::ActionInvoked(na) => self.notification.action_message(na, &self.path),
+                DbusSignal::Portal(event) => self.services.portal.event_message(event),
+                DbusSignal::StatusNotifier(act) => self.services.watcher.activation_message(act),
             };
-            c.send(sig.to_emit_message(&path))
-                .expect("sending DBus signal failed");
+            if let Some(msg) = msg {
+                c.send(msg)
+                    .map_err(|_| "sending DBus signal failed".to_string())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Source for DbusListener {
+    fn fds(&self) -> Vec<libc::pollfd> {
+        let mut fds = vec![libc::pollfd {
+            fd: self.receiver.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+
+        // The set of fds libdbus wants us to watch may change at any time,
+        // so we rebuild the list on each iteration.
+        if let Some(c) = &self.conn {
+            fds.extend(c.watch_fds().iter().map(|w| libc::pollfd {
+                fd: w.fd(),
+                events: (if w.readable() { libc::POLLIN } else { 0 })
+                    | (if w.writable() { libc::POLLOUT } else { 0 }),
+                revents: 0,
+            }));
+        }
+
+        fds
+    }
+
+    fn handle(&mut self, pfd: &libc::pollfd) -> Result<(), String> {
+        let result = if pfd.fd == self.receiver.as_raw_fd() {
+            self.handle_signals()
+        } else {
+            match &self.conn {
+                Some(c) => self.handle_watch(c, pfd),
+                None => Ok(()),
+            }
+        };
+
+        if let Err(err) = result {
+            self.disconnect(&err);
+        }
+        Ok(())
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match &self.conn {
+            Some(_) => self.services.watcher.next_refresh(),
+            None => self.retry_at,
+        }
+    }
+
+    fn handle_deadline(&mut self) -> Result<(), String> {
+        match &self.conn {
+            Some(c) => self.services.watcher.refresh(c),
+            None => self.connect(),
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_id_skips_zero_on_wraparound() {
+        let mut last = u32::MAX;
+        assert_eq!(next_id(&mut last, |_| false), 1);
+        assert_eq!(last, 1);
+    }
+
+    #[test]
+    fn next_id_skips_ids_in_use() {
+        let mut last = u32::MAX - 1;
+        let id = next_id(&mut last, |id| id == u32::MAX || id == 1);
+        assert_eq!(id, 2);
+    }
+
+    #[test]
+    fn assign_new_notifications() {
+        let mut ids = NotificationIds::default();
+        assert_eq!(ids.assign(":1.1", 0), (1, 1, 0));
+        assert_eq!(ids.assign(":1.1", 0), (2, 2, 0));
+        assert_eq!(ids.host_id(2), Some(2));
+        assert_eq!(ids.guest_id(1), Some(1));
+    }
+
+    #[test]
+    fn assign_replaces_own_notification() {
+        let mut ids = NotificationIds::default();
+        let (guest_id, host_id, _) = ids.assign(":1.1", 0);
+        assert_eq!(ids.assign(":1.1", guest_id), (guest_id, host_id, host_id));
+    }
+
+    #[test]
+    fn assign_ignores_replaces_id_of_others() {
+        let mut ids = NotificationIds::default();
+        let (guest_id, host_id, _) = ids.assign(":1.1", 0);
+        let (other_guest_id, other_host_id, replaced) = ids.assign(":1.2", guest_id);
+        assert_ne!(other_guest_id, guest_id);
+        assert_ne!(other_host_id, host_id);
+        assert_eq!(replaced, 0);
+        assert_eq!(ids.host_id(guest_id), Some(host_id));
+    }
+
+    #[test]
+    fn assign_ignores_unknown_replaces_id() {
+        let mut ids = NotificationIds::default();
+        assert_eq!(ids.assign(":1.1", 42), (1, 1, 0));
+    }
+
+    #[test]
+    fn removed_ids_are_forgotten() {
+        let mut ids = NotificationIds::default();
+        let (guest_id, host_id, _) = ids.assign(":1.1", 0);
+        assert_eq!(ids.remove(host_id), Some(guest_id));
+        assert_eq!(ids.host_id(guest_id), None);
+        assert_eq!(ids.remove(host_id), None);
+        // A removed notification can't be replaced anymore.
+        assert_eq!(ids.assign(":1.1", guest_id).2, 0);
+    }
+
+    #[test]
+    fn assign_wraps_around_ids_in_use() {
+        let mut ids = NotificationIds::default();
+        let (guest_id, host_id, _) = ids.assign(":1.1", 0);
+        ids.last_guest_id = u32::MAX;
+        ids.last_host_id = u32::MAX;
+        let (new_guest_id, new_host_id, _) = ids.assign(":1.1", 0);
+        assert_eq!((guest_id, host_id), (1, 1));
+        assert_eq!((new_guest_id, new_host_id), (2, 2));
     }
 }
@@ -0,0 +1,111 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// Classification of hotplugged devices, so the Host can show which ones
+// are attached to the VM.
+//
+
+use flatkvm_qemu::devices::{DeviceAction, DeviceClass, DeviceEvent};
+use log::debug;
+
+use crate::event_channel::EventSender;
+use crate::message::Message;
+use crate::notification_filter::sanitize_text;
+use crate::udevmon::UdevMonitor;
+
+const MAX_NAME_LEN: usize = 64;
+
+fn property(event: &udev::Event, name: &str) -> Option<String> {
+    event
+        .property_value(name)
+        .and_then(|v| v.to_str())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+}
+
+fn sysname(event: &udev::Event) -> &str {
+    event.sysname().to_str().unwrap_or("")
+}
+
+// Returns the class of the device if it's one the Host cares about. Each
+// physical device generates events for many nodes (interfaces, event and
+// mouse nodes, PCM streams...), so only one of them is picked.
+fn classify(event: &udev::Event) -> Option<DeviceClass> {
+    let subsystem = event.subsystem().and_then(|s| s.to_str())?;
+    let devtype = event.devtype().and_then(|d| d.to_str());
+
+    match subsystem {
+        "usb" if devtype == Some("usb_device") => Some(DeviceClass::Usb),
+        "input" if sysname(event).starts_with("input") => Some(DeviceClass::Input),
+        "sound" if sysname(event).starts_with("card") => Some(DeviceClass::Audio),
+        _ => None,
+    }
+}
+
+// Returns a human readable name for the device, preferring the ones from
+// the hwdb over the ones reported by the device itself.
+fn device_name(event: &udev::Event, class: &DeviceClass) -> String {
+    let candidates: &[&str] = match class {
+        DeviceClass::Usb => &["ID_MODEL_FROM_DATABASE", "ID_MODEL"],
+        DeviceClass::Input => &["NAME", "ID_MODEL"],
+        DeviceClass::Audio => &["ID_MODEL_FROM_DATABASE", "ID_MODEL", "ID_ID"],
+    };
+
+    let name = candidates
+        .iter()
+        .filter_map(|p| property(event, p))
+        .next()
+        .unwrap_or_else(|| sysname(event).to_string());
+    // The kernel reports input device names quoted.
+    sanitize_text(name.trim_matches('"'), MAX_NAME_LEN)
+}
+
+fn device_event(event: &udev::Event) -> Option<DeviceEvent> {
+    let action = match event.event_type() {
+        udev::EventType::Add => DeviceAction::Added,
+        udev::EventType::Remove => DeviceAction::Removed,
+        _ => return None,
+    };
+    let class = classify(event)?;
+
+    Some(DeviceEvent {
+        action,
+        name: device_name(event, &class),
+        class,
+        // Used by the Host to match the removal with the addition.
+        id: event.syspath().to_str().unwrap_or("").to_string(),
+        vendor_id: property(event, "ID_VENDOR_ID"),
+        product_id: property(event, "ID_MODEL_ID"),
+    })
+}
+
+/// Registers the handlers forwarding to the Host the hotplug events of USB,
+/// input and audio devices.
+pub fn add_udev_handlers(monitor: &mut UdevMonitor, sender: EventSender<Message>) {
+    for subsystem in ["usb", "input", "sound"].iter() {
+        let sender = sender.clone();
+        monitor.add_handler(
+            subsystem,
+            Box::new(move |event: &udev::Event| {
+                if let Some(de) = device_event(event) {
+                    debug!("device event: {:?} {:?} {}", de.action, de.class, de.name);
+                    sender.send(Message::DeviceEvent(de)).unwrap();
+                }
+            }),
+        );
+    }
+}
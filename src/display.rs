@@ -0,0 +1,633 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// Guest display configuration through RandR, using the xrandr utility, and
+// the X resources database for the DPI.
+//
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use flatkvm_qemu::agent::AgentOutputLayout;
+use log::{debug, error};
+
+use crate::udevmon::UdevMonitor;
+
+/// What to do with the outputs when the set of connected monitors or their
+/// preferred modes change.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModePolicy {
+    /// Let xrandr pick the mode for each connected output.
+    Auto,
+    /// Explicitly apply the preferred mode reported by each output.
+    Preferred,
+    /// Leave the display configuration alone.
+    None,
+}
+
+impl FromStr for ModePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ModePolicy::Auto),
+            "preferred" => Ok(ModePolicy::Preferred),
+            "none" => Ok(ModePolicy::None),
+            _ => Err(format!("unknown display policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DisplayConfig {
+    pub policy: ModePolicy,
+    /// Outputs to be managed. If empty, all of them are.
+    pub outputs: Vec<String>,
+}
+
+impl DisplayConfig {
+    fn manages(&self, output: &str) -> bool {
+        self.outputs.is_empty() || self.outputs.iter().any(|o| o == output)
+    }
+}
+
+#[derive(Default)]
+struct HostRequests {
+    layout: Option<Vec<AgentOutputLayout>>,
+    resolution: Option<(u32, u32)>,
+}
+
+/// Display configuration last applied at the request of the Host. It's
+/// shared with the udev handler, so it can be restored when the outputs
+/// change instead of applying the policy over it.
+#[derive(Clone, Default)]
+pub struct HostState(Arc<Mutex<HostRequests>>);
+
+#[derive(Debug)]
+pub struct Output {
+    pub name: String,
+    pub connected: bool,
+    pub modes: Vec<String>,
+    pub preferred_mode: Option<String>,
+    pub current_mode: Option<String>,
+}
+
+fn run_xrandr(args: &[String]) -> Result<(), String> {
+    debug!("running xrandr with args: {:?}", args);
+    let exit_status = Command::new("xrandr")
+        .args(args)
+        .status()
+        .map_err(|err| err.to_string())?;
+
+    match exit_status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(format!("xrandr exit code: {}", code)),
+        None => Err("xrandr killed by signal".to_string()),
+    }
+}
+
+fn parse_outputs(query: &str) -> Vec<Output> {
+    let mut outputs: Vec<Output> = Vec::new();
+
+    for line in query.lines() {
+        if line.starts_with("Screen ") {
+            continue;
+        }
+
+        let mut tokens = line.split_whitespace();
+        if !line.starts_with(char::is_whitespace) {
+            if let (Some(name), Some(state)) = (tokens.next(), tokens.next()) {
+                outputs.push(Output {
+                    name: name.to_string(),
+                    connected: state == "connected",
+                    modes: Vec::new(),
+                    preferred_mode: None,
+                    current_mode: None,
+                });
+            }
+            continue;
+        }
+
+        // Mode lines are indented, and mark the current mode with '*' and
+        // the preferred one with '+' after the refresh rates.
+        let output = match outputs.last_mut() {
+            Some(output) => output,
+            None => continue,
+        };
+        let mode = match tokens.next() {
+            Some(mode) => mode.to_string(),
+            None => continue,
+        };
+        let rates: String = tokens.collect();
+        if rates.contains('+') {
+            output.preferred_mode = Some(mode.clone());
+        }
+        if rates.contains('*') {
+            output.current_mode = Some(mode.clone());
+        }
+        output.modes.push(mode);
+    }
+
+    outputs
+}
+
+/// Returns the outputs known by RandR, with their modes.
+pub fn query_outputs() -> Result<Vec<Output>, String> {
+    let output = Command::new("xrandr")
+        .arg("--query")
+        .output()
+        .map_err(|err| err.to_string())?;
+    if !output.status.success() {
+        return Err("xrandr --query failed".to_string());
+    }
+
+    Ok(parse_outputs(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Configures the outputs managed according to the policy, enabling the
+/// connected ones and disabling those that have been disconnected.
+pub fn apply_policy(config: &DisplayConfig) -> Result<(), String> {
+    if config.policy == ModePolicy::None {
+        return Ok(());
+    }
+
+    let mut args = Vec::new();
+    for output in query_outputs()?.iter().filter(|o| config.manages(&o.name)) {
+        args.push("--output".to_string());
+        args.push(output.name.clone());
+
+        match (output.connected, config.policy, &output.preferred_mode) {
+            (false, _, _) => args.push("--off".to_string()),
+            (true, ModePolicy::Preferred, Some(mode)) => {
+                args.push("--mode".to_string());
+                args.push(mode.clone());
+            }
+            (true, _, _) => args.push("--auto".to_string()),
+        }
+    }
+
+    if args.is_empty() {
+        return Ok(());
+    }
+    run_xrandr(&args)
+}
+
+fn parse_mode(mode: &str) -> Option<(u32, u32)> {
+    let mut dims = mode.split(['x', '_']);
+    let width = dims.next()?.parse().ok()?;
+    let height = dims.next()?.parse().ok()?;
+    Some((width, height))
+}
+
+// Creates a new mode with the timings computed by cvt, and makes it
+// available for the output.
+fn add_mode(output: &str, name: &str, width: u32, height: u32) -> Result<(), String> {
+    let cvt = Command::new("cvt")
+        .arg(width.to_string())
+        .arg(height.to_string())
+        .output()
+        .map_err(|err| err.to_string())?;
+    let cvt = String::from_utf8_lossy(&cvt.stdout);
+
+    // We're only interested in the timings after the mode name in the line
+    // looking like: Modeline "1920x1080_60.00"  173.00  1920 2048 ...
+    let timings: Vec<String> = cvt
+        .lines()
+        .find(|l| l.starts_with("Modeline"))
+        .ok_or("can't compute mode timings".to_string())?
+        .split_whitespace()
+        .skip(2)
+        .map(|t| t.to_string())
+        .collect();
+
+    let mut args = vec!["--newmode".to_string(), name.to_string()];
+    args.extend(timings);
+    // The mode may already exist, but not be available for this output.
+    if let Err(err) = run_xrandr(&args) {
+        debug!("can't create mode {}: {}", name, err);
+    }
+
+    run_xrandr(&[
+        "--addmode".to_string(),
+        output.to_string(),
+        name.to_string(),
+    ])
+}
+
+// Returns the name of a mode with the given resolution available for the
+// output, creating it if needed.
+fn ensure_mode(output: &Output, width: u32, height: u32) -> Result<String, String> {
+    match output
+        .modes
+        .iter()
+        .find(|m| parse_mode(m) == Some((width, height)))
+    {
+        Some(mode) => Ok(mode.clone()),
+        None => {
+            let name = format!("{}x{}", width, height);
+            add_mode(&output.name, &name, width, height)?;
+            Ok(name)
+        }
+    }
+}
+
+/// Returns the resolution of the mode currently set on the first connected
+/// output managed.
+pub fn current_resolution(config: &DisplayConfig) -> Result<(u32, u32), String> {
+    query_outputs()?
+        .iter()
+        .find(|o| o.connected && config.manages(&o.name))
+        .and_then(|o| o.current_mode.as_ref())
+        .and_then(|m| parse_mode(m))
+        .ok_or("output has no mode set".to_string())
+}
+
+fn switch_resolution(
+    config: &DisplayConfig,
+    width: u32,
+    height: u32,
+) -> Result<(u32, u32), String> {
+    let outputs = query_outputs()?;
+    let output = outputs
+        .iter()
+        .find(|o| o.connected && config.manages(&o.name))
+        .ok_or("no connected outputs".to_string())?;
+
+    let mode = ensure_mode(output, width, height)?;
+    run_xrandr(&[
+        "--output".to_string(),
+        output.name.clone(),
+        "--mode".to_string(),
+        mode,
+    ])
+    .map_err(|err| {
+        format!(
+            "can't switch {} to {}x{}: {}",
+            output.name, width, height, err
+        )
+    })?;
+
+    // Report whatever mode ended being used.
+    current_resolution(config)
+}
+
+/// Switches the first connected output managed to the given resolution,
+/// creating a new mode for it if needed. Returns the resolution actually
+/// applied.
+pub fn set_resolution(
+    config: &DisplayConfig,
+    state: &HostState,
+    width: u32,
+    height: u32,
+) -> Result<(u32, u32), String> {
+    let result = switch_resolution(config, width, height)?;
+    state.0.lock().unwrap().resolution = Some((width, height));
+    Ok(result)
+}
+
+// Pairs each entry of the layout with the connected output it applies to.
+// Entries naming an output go to that one, and the rest are handed out to
+// the remaining outputs in the order RandR reports them.
+fn assign_layout<'a>(
+    outputs: &[&'a Output],
+    layout: &'a [AgentOutputLayout],
+) -> Result<Vec<(&'a Output, &'a AgentOutputLayout)>, String> {
+    let mut assigned: Vec<(&Output, &AgentOutputLayout)> = Vec::new();
+    for entry in layout.iter() {
+        let name = match &entry.name {
+            Some(name) => name,
+            None => continue,
+        };
+        let output = outputs
+            .iter()
+            .find(|o| o.name == *name)
+            .ok_or(format!("output {} not available", name))?;
+        if assigned.iter().any(|(o, _)| o.name == *name) {
+            return Err(format!("output {} appears twice in layout", name));
+        }
+        assigned.push((*output, entry));
+    }
+
+    let free: Vec<&Output> = outputs
+        .iter()
+        .cloned()
+        .filter(|o| !assigned.iter().any(|(a, _)| a.name == o.name))
+        .collect();
+    let mut free = free.into_iter();
+    for entry in layout.iter().filter(|e| e.name.is_none()) {
+        let output = free.next().ok_or(format!(
+            "layout has {} outputs, but only {} are available",
+            layout.len(),
+            outputs.len()
+        ))?;
+        assigned.push((output, entry));
+    }
+
+    Ok(assigned)
+}
+
+fn configure_outputs(config: &DisplayConfig, layout: &[AgentOutputLayout]) -> Result<(), String> {
+    let outputs: Vec<Output> = query_outputs()?
+        .into_iter()
+        .filter(|o| config.manages(&o.name))
+        .collect();
+    let connected: Vec<&Output> = outputs.iter().filter(|o| o.connected).collect();
+    let assigned = assign_layout(&connected, layout)?;
+
+    let mut args = Vec::new();
+    for output in outputs.iter() {
+        args.push("--output".to_string());
+        args.push(output.name.clone());
+
+        match assigned.iter().find(|(o, _)| o.name == output.name) {
+            Some((_, entry)) if entry.enabled => {
+                args.push("--mode".to_string());
+                args.push(ensure_mode(output, entry.width, entry.height)?);
+                args.push("--pos".to_string());
+                args.push(format!("{}x{}", entry.x, entry.y));
+                if entry.primary {
+                    args.push("--primary".to_string());
+                }
+            }
+            _ => args.push("--off".to_string()),
+        }
+    }
+
+    run_xrandr(&args)
+}
+
+/// Configures the connected outputs managed following the layout sent by
+/// the Host. Outputs without an entry, or disconnected, are disabled. As
+/// the layout sets the modes, it replaces any resolution set before.
+pub fn apply_layout(
+    config: &DisplayConfig,
+    state: &HostState,
+    layout: Vec<AgentOutputLayout>,
+) -> Result<(), String> {
+    configure_outputs(config, &layout)?;
+    let mut requests = state.0.lock().unwrap();
+    requests.layout = Some(layout);
+    requests.resolution = None;
+    Ok(())
+}
+
+// Brings the outputs back to the configuration requested by the Host, if
+// any, or applies the policy otherwise. If the layout no longer fits the
+// outputs connected, it's kept in case they come back.
+fn restore(config: &DisplayConfig, state: &HostState) -> Result<(), String> {
+    let requests = state.0.lock().unwrap();
+    match &requests.layout {
+        Some(layout) => {
+            if let Err(err) = configure_outputs(config, layout) {
+                error!("can't restore display layout: {}", err);
+                apply_policy(config)?;
+            }
+        }
+        None => apply_policy(config)?,
+    }
+    if let Some((width, height)) = requests.resolution {
+        switch_resolution(config, width, height)?;
+    }
+    Ok(())
+}
+
+/// Sets Xft.dpi in the resources database according to the scale factor,
+/// so fonts are rendered at the right size on HiDPI Hosts.
+pub fn set_scale(scale: f64) -> Result<(), String> {
+    let dpi = (96.0 * scale).round() as u32;
+    debug!("setting Xft.dpi to {}", dpi);
+
+    let mut child = Command::new("xrdb")
+        .arg("-merge")
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| err.to_string())?;
+    if let Some(stdin) = child.stdin.as_mut() {
+        writeln!(stdin, "Xft.dpi: {}", dpi).map_err(|err| err.to_string())?;
+    }
+    // Close stdin so xrdb can finish.
+    drop(child.stdin.take());
+
+    let exit_status = child.wait().map_err(|err| err.to_string())?;
+    match exit_status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(format!("xrdb exit code: {}", code)),
+        None => Err("xrdb killed by signal".to_string()),
+    }
+}
+
+/// Returns the environment variables telling toolkits to scale their UI.
+/// GTK only supports integer scales, so the rest is applied to the fonts.
+/// Both GTK and Qt already scale fonts using Xft.dpi, so undo that here to
+/// avoid scaling them twice.
+pub fn scale_env(scale: f64) -> Vec<(String, String)> {
+    let gdk_scale = scale.floor().max(1.0);
+    vec![
+        ("GDK_SCALE".to_string(), format!("{}", gdk_scale)),
+        (
+            "GDK_DPI_SCALE".to_string(),
+            format!("{:.2}", 1.0 / gdk_scale),
+        ),
+        ("QT_SCALE_FACTOR".to_string(), format!("{:.2}", scale)),
+        ("QT_FONT_DPI".to_string(), "96".to_string()),
+    ]
+}
+
+/// Registers the handler configuring the outputs whenever a DRM card
+/// reports a change in its connectors.
+pub fn add_udev_handlers(monitor: &mut UdevMonitor, config: DisplayConfig, state: HostState) {
+    monitor.add_handler(
+        "drm",
+        Box::new(move |event: &udev::Event| {
+            // Hotplug and mode change events are reported on the card
+            // itself, not on its connectors (i.e. "card0" but not
+            // "card0-Virtual-1").
+            let sysname = event.sysname().to_str().unwrap_or("");
+            if !sysname.starts_with("card") || sysname.contains('-') {
+                return;
+            }
+            if let Err(err) = restore(&config, &state) {
+                error!("error configuring outputs: {}", err);
+            }
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &str = "\
+Screen 0: minimum 320 x 200, current 1920 x 1080, maximum 8192 x 8192
+Virtual-1 connected primary 1920x1080+0+0 (normal left inverted right x axis y axis) 0mm x 0mm
+   1024x768      60.00 +
+   1920x1080     60.00*
+   1280x720_60.00  59.86
+Virtual-2 disconnected (normal left inverted right x axis y axis)
+Virtual-3 connected (normal left inverted right x axis y axis)
+   800x600       60.32 +  56.25
+";
+
+    #[test]
+    fn parse_outputs_connected_state() {
+        let outputs = parse_outputs(QUERY);
+        let names: Vec<&str> = outputs.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(names, vec!["Virtual-1", "Virtual-2", "Virtual-3"]);
+        assert!(outputs[0].connected);
+        assert!(!outputs[1].connected);
+        assert!(outputs[2].connected);
+    }
+
+    #[test]
+    fn parse_outputs_modes() {
+        let outputs = parse_outputs(QUERY);
+        assert_eq!(
+            outputs[0].modes,
+            vec!["1024x768", "1920x1080", "1280x720_60.00"]
+        );
+        assert!(outputs[1].modes.is_empty());
+        assert_eq!(outputs[2].modes, vec!["800x600"]);
+    }
+
+    #[test]
+    fn parse_outputs_mode_markers() {
+        let outputs = parse_outputs(QUERY);
+        assert_eq!(outputs[0].preferred_mode.as_deref(), Some("1024x768"));
+        assert_eq!(outputs[0].current_mode.as_deref(), Some("1920x1080"));
+        assert_eq!(outputs[1].preferred_mode, None);
+        assert_eq!(outputs[1].current_mode, None);
+        assert_eq!(outputs[2].preferred_mode.as_deref(), Some("800x600"));
+        assert_eq!(outputs[2].current_mode, None);
+    }
+
+    #[test]
+    fn parse_outputs_empty() {
+        assert!(parse_outputs("").is_empty());
+        assert!(parse_outputs("   1024x768      60.00 +\n").is_empty());
+    }
+
+    fn output(name: &str) -> Output {
+        Output {
+            name: name.to_string(),
+            connected: true,
+            modes: Vec::new(),
+            preferred_mode: None,
+            current_mode: None,
+        }
+    }
+
+    fn entry(name: Option<&str>, x: i32) -> AgentOutputLayout {
+        AgentOutputLayout {
+            name: name.map(|n| n.to_string()),
+            enabled: true,
+            width: 1024,
+            height: 768,
+            x,
+            y: 0,
+            primary: false,
+        }
+    }
+
+    // Returns the name of the output each entry was assigned to, in the
+    // order of the entries.
+    fn assigned_names(outputs: &[&Output], layout: &[AgentOutputLayout]) -> Vec<String> {
+        let assigned = assign_layout(outputs, layout).unwrap();
+        layout
+            .iter()
+            .map(|e| {
+                let (o, _) = assigned.iter().find(|(_, a)| std::ptr::eq(*a, e)).unwrap();
+                o.name.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn assign_layout_unnamed_in_order() {
+        let (a, b) = (output("Virtual-1"), output("Virtual-2"));
+        let layout = vec![entry(None, 0), entry(None, 1024)];
+        assert_eq!(
+            assigned_names(&[&a, &b], &layout),
+            vec!["Virtual-1", "Virtual-2"]
+        );
+    }
+
+    #[test]
+    fn assign_layout_named() {
+        let (a, b) = (output("Virtual-1"), output("Virtual-2"));
+        let layout = vec![entry(Some("Virtual-2"), 0), entry(Some("Virtual-1"), 1024)];
+        assert_eq!(
+            assigned_names(&[&a, &b], &layout),
+            vec!["Virtual-2", "Virtual-1"]
+        );
+    }
+
+    #[test]
+    fn assign_layout_mixed() {
+        let (a, b, c) = (
+            output("Virtual-1"),
+            output("Virtual-2"),
+            output("Virtual-3"),
+        );
+        let layout = vec![
+            entry(None, 0),
+            entry(Some("Virtual-1"), 1024),
+            entry(None, 2048),
+        ];
+        assert_eq!(
+            assigned_names(&[&a, &b, &c], &layout),
+            vec!["Virtual-2", "Virtual-1", "Virtual-3"]
+        );
+    }
+
+    #[test]
+    fn assign_layout_fewer_entries() {
+        let (a, b) = (output("Virtual-1"), output("Virtual-2"));
+        let layout = vec![entry(None, 0)];
+        assert_eq!(assign_layout(&[&a, &b], &layout).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn assign_layout_unknown_name() {
+        let a = output("Virtual-1");
+        let layout = vec![entry(Some("Virtual-9"), 0)];
+        assert!(assign_layout(&[&a], &layout).is_err());
+    }
+
+    #[test]
+    fn assign_layout_duplicate_name() {
+        let (a, b) = (output("Virtual-1"), output("Virtual-2"));
+        let layout = vec![entry(Some("Virtual-1"), 0), entry(Some("Virtual-1"), 1024)];
+        assert!(assign_layout(&[&a, &b], &layout).is_err());
+    }
+
+    #[test]
+    fn assign_layout_too_many_entries() {
+        let a = output("Virtual-1");
+        let layout = vec![entry(Some("Virtual-1"), 0), entry(None, 1024)];
+        assert!(assign_layout(&[&a], &layout).is_err());
+    }
+
+    #[test]
+    fn parse_mode_names() {
+        assert_eq!(parse_mode("1920x1080"), Some((1920, 1080)));
+        assert_eq!(parse_mode("1280x720_60.00"), Some((1280, 720)));
+        assert_eq!(parse_mode("1920x"), None);
+        assert_eq!(parse_mode("custom"), None);
+    }
+}
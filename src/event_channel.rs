@@ -0,0 +1,126 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// An mpsc channel paired with an eventfd, so the receiving side can wait
+// for messages with poll() alongside other file descriptors.
+//
+
+use std::fmt;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc::{self, Receiver, Sender, TryIter};
+use std::sync::Arc;
+
+#[derive(Debug)]
+struct EventFd(RawFd);
+
+impl EventFd {
+    fn new() -> io::Result<EventFd> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd(fd))
+    }
+
+    fn signal(&self) -> io::Result<()> {
+        let val: u64 = 1;
+        // Safe because we're passing a valid buffer of the right size.
+        let ret = unsafe { libc::write(self.0, &val as *const u64 as *const libc::c_void, 8) };
+        // The counter can only overflow if the receiver has stopped
+        // draining it, in which case there's no point in signaling again.
+        if ret < 0 && io::Error::last_os_error().kind() != io::ErrorKind::WouldBlock {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn clear(&self) {
+        let mut val: u64 = 0;
+        // Safe because we're passing a valid buffer of the right size. The
+        // only expected error is EAGAIN, meaning the counter was already 0.
+        unsafe { libc::read(self.0, &mut val as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+pub struct EventSender<T> {
+    sender: Sender<T>,
+    efd: Arc<EventFd>,
+}
+
+// Like the one for mpsc::Sender, this doesn't require T to implement Debug.
+impl<T> fmt::Debug for EventSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("EventSender { .. }")
+    }
+}
+
+impl<T> Clone for EventSender<T> {
+    fn clone(&self) -> Self {
+        EventSender {
+            sender: self.sender.clone(),
+            efd: self.efd.clone(),
+        }
+    }
+}
+
+impl<T> EventSender<T> {
+    pub fn send(&self, t: T) -> Result<(), String> {
+        self.sender.send(t).map_err(|err| err.to_string())?;
+        self.efd.signal().map_err(|err| err.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub struct EventReceiver<T> {
+    receiver: Receiver<T>,
+    efd: Arc<EventFd>,
+}
+
+impl<T> EventReceiver<T> {
+    /// Returns an iterator over the messages pending in the channel,
+    /// without blocking. Must be called each time the fd becomes readable.
+    pub fn try_iter(&self) -> TryIter<T> {
+        self.efd.clear();
+        self.receiver.try_iter()
+    }
+}
+
+impl<T> AsRawFd for EventReceiver<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.efd.0
+    }
+}
+
+pub fn channel<T>() -> io::Result<(EventSender<T>, EventReceiver<T>)> {
+    let efd = Arc::new(EventFd::new()?);
+    let (sender, receiver) = mpsc::channel();
+
+    Ok((
+        EventSender {
+            sender,
+            efd: efd.clone(),
+        },
+        EventReceiver { receiver, efd },
+    ))
+}
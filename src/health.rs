@@ -0,0 +1,167 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// Guest health and resource usage, as reported to the Host.
+//
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+
+use flatkvm_qemu::agent::{AgentAppUsage, AgentDiskUsage, AgentStatusReport};
+use log::debug;
+
+use crate::apps::{self, Apps};
+
+// Indexes of utime and stime in the fields returned by apps::stat_fields.
+const STAT_UTIME: usize = 11;
+const STAT_STIME: usize = 12;
+
+fn read_proc(name: &str) -> Result<String, String> {
+    fs::read_to_string(format!("/proc/{}", name)).map_err(|err| err.to_string())
+}
+
+// Returns the values of /proc/meminfo, in bytes.
+fn meminfo() -> Result<HashMap<String, u64>, String> {
+    let mut values = HashMap::new();
+    for line in read_proc("meminfo")?.lines() {
+        let mut tokens = line.split_whitespace();
+        let (key, value) = match (tokens.next(), tokens.next()) {
+            (Some(key), Some(value)) => (key.trim_end_matches(':'), value),
+            _ => continue,
+        };
+        if let Ok(value) = value.parse::<u64>() {
+            // All values we care about are reported in kB.
+            values.insert(key.to_string(), value * 1024);
+        }
+    }
+    Ok(values)
+}
+
+fn disk_usage(path: &str) -> Option<AgentDiskUsage> {
+    let cpath = CString::new(path).ok()?;
+    // Safe because statvfs is plain old data, and is only read if the call
+    // succeeds.
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut st) } != 0 {
+        debug!("can't stat {}", path);
+        return None;
+    }
+
+    let frsize = st.f_frsize as u64;
+    Some(AgentDiskUsage {
+        path: path.to_string(),
+        total: st.f_blocks as u64 * frsize,
+        available: st.f_bavail as u64 * frsize,
+    })
+}
+
+// Adds up the CPU time and resident memory of all processes of the app.
+// Processes exiting while we walk the tree are just skipped.
+fn app_usage(pid: u32) -> AgentAppUsage {
+    // Safe because sysconf doesn't touch our memory.
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+
+    let mut usage = AgentAppUsage {
+        pid,
+        processes: 0,
+        cpu_time_ms: 0,
+        rss: 0,
+    };
+    for p in apps::process_tree(pid) {
+        let fields = match apps::stat_fields(p) {
+            Some(fields) => fields,
+            None => continue,
+        };
+        let cpu_ticks: u64 = [STAT_UTIME, STAT_STIME]
+            .iter()
+            .filter_map(|&i| fields.get(i).and_then(|f| f.parse::<u64>().ok()))
+            .sum();
+        // The second field of statm is the number of resident pages.
+        let resident: u64 = fs::read_to_string(format!("/proc/{}/statm", p))
+            .ok()
+            .and_then(|statm| statm.split_whitespace().nth(1)?.parse().ok())
+            .unwrap_or(0);
+
+        usage.processes += 1;
+        usage.cpu_time_ms += cpu_ticks * 1000 / ticks;
+        usage.rss += resident * page_size;
+    }
+    usage
+}
+
+/// Collects the status of the guest, including the disk usage of the root
+/// filesystem and the shared directories, and the resources used by each
+/// app.
+pub fn status_report(apps: &Apps, mounts: &[String]) -> Result<AgentStatusReport, String> {
+    let uptime = read_proc("uptime")?;
+    let uptime_secs = uptime
+        .split_whitespace()
+        .next()
+        .and_then(|u| u.parse::<f64>().ok())
+        .ok_or("can't parse uptime".to_string())? as u64;
+
+    let load: Vec<f64> = read_proc("loadavg")?
+        .split_whitespace()
+        .take(3)
+        .filter_map(|l| l.parse().ok())
+        .collect();
+    if load.len() != 3 {
+        return Err("can't parse load average".to_string());
+    }
+
+    let mem = meminfo()?;
+    let mem_value = |key: &str| mem.get(key).cloned().unwrap_or(0);
+
+    let disks = ["/".to_string()]
+        .iter()
+        .chain(mounts.iter())
+        .filter_map(|path| disk_usage(path))
+        .collect();
+
+    Ok(AgentStatusReport {
+        uptime_secs,
+        load1: load[0],
+        load5: load[1],
+        load15: load[2],
+        mem_total: mem_value("MemTotal"),
+        mem_available: mem_value("MemAvailable"),
+        swap_total: mem_value("SwapTotal"),
+        swap_free: mem_value("SwapFree"),
+        disks,
+        apps: apps.pids().into_iter().map(app_usage).collect(),
+        error: None,
+    })
+}
+
+/// Returns the report telling the Host the status couldn't be collected.
+pub fn error_report(err: String) -> AgentStatusReport {
+    AgentStatusReport {
+        uptime_secs: 0,
+        load1: 0.0,
+        load5: 0.0,
+        load15: 0.0,
+        mem_total: 0,
+        mem_available: 0,
+        swap_total: 0,
+        swap_free: 0,
+        disks: Vec::new(),
+        apps: Vec::new(),
+        error: Some(err),
+    }
+}
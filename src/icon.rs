@@ -0,0 +1,482 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// Resolution of notification and status icons into PNG images that can be
+// relayed to the Host.
+//
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use dbus::arg::{RefArg, Variant};
+use log::debug;
+use png::HasParameters;
+
+// Icons are scaled down to fit in a square of this size, so the amount of
+// data sent to the Host for each notification is bounded.
+const MAX_ICON_SIZE: u32 = 64;
+// Icon files bigger than this are ignored without even trying to decode them.
+const MAX_ICON_FILE_SIZE: u64 = 512 * 1024;
+
+const ICON_THEMES: [&str; 2] = ["Adwaita", "hicolor"];
+const ICON_SIZES: [&str; 6] = ["64x64", "48x48", "96x96", "128x128", "256x256", "32x32"];
+
+struct RgbaImage {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl RgbaImage {
+    // Nearest-neighbour downscaling, preserving the aspect ratio.
+    fn bounded(self) -> RgbaImage {
+        if self.width <= MAX_ICON_SIZE && self.height <= MAX_ICON_SIZE {
+            return self;
+        }
+
+        let (width, height) = if self.width >= self.height {
+            (
+                MAX_ICON_SIZE,
+                (self.height * MAX_ICON_SIZE / self.width).max(1),
+            )
+        } else {
+            (
+                (self.width * MAX_ICON_SIZE / self.height).max(1),
+                MAX_ICON_SIZE,
+            )
+        };
+
+        let mut data = Vec::with_capacity((width * height * 4) as usize);
+        for y in 0..height {
+            let sy = y * self.height / height;
+            for x in 0..width {
+                let sx = x * self.width / width;
+                let offset = ((sy * self.width + sx) * 4) as usize;
+                data.extend_from_slice(&self.data[offset..offset + 4]);
+            }
+        }
+
+        RgbaImage {
+            width,
+            height,
+            data,
+        }
+    }
+
+    fn to_png(&self) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut buf, self.width, self.height);
+            encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+            writer
+                .write_image_data(&self.data)
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(buf)
+    }
+}
+
+fn to_rgba(data: &[u8], channels: usize, pixels: usize) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(pixels * 4);
+    for px in data.chunks(channels).take(pixels) {
+        match channels {
+            1 => rgba.extend_from_slice(&[px[0], px[0], px[0], 255]),
+            2 => rgba.extend_from_slice(&[px[0], px[0], px[0], px[1]]),
+            3 => rgba.extend_from_slice(&[px[0], px[1], px[2], 255]),
+            _ => rgba.extend_from_slice(&px[..4]),
+        }
+    }
+    rgba
+}
+
+fn next_int<'a>(
+    fields: &mut dyn Iterator<Item = &'a dyn RefArg>,
+    name: &str,
+) -> Result<i64, String> {
+    fields
+        .next()
+        .and_then(|f| f.as_i64())
+        .ok_or(format!("image-data: missing or invalid {}", name))
+}
+
+// Decodes an "image-data" hint, with the (iiibiiay) signature described in
+// the Desktop Notifications Specification.
+fn decode_image_data(arg: &dyn RefArg) -> Result<RgbaImage, String> {
+    let mut fields = arg
+        .as_iter()
+        .ok_or("image-data: not a structure".to_string())?;
+
+    let width = next_int(&mut fields, "width")?;
+    let height = next_int(&mut fields, "height")?;
+    let rowstride = next_int(&mut fields, "rowstride")?;
+    let has_alpha = next_int(&mut fields, "has_alpha")? != 0;
+    let bits_per_sample = next_int(&mut fields, "bits_per_sample")?;
+    let channels = next_int(&mut fields, "channels")?;
+    let data: Vec<u8> = fields
+        .next()
+        .and_then(|f| f.as_iter())
+        .ok_or("image-data: missing data".to_string())?
+        .filter_map(|b| b.as_u64().map(|b| b as u8))
+        .collect();
+
+    let expected_channels = if has_alpha { 4 } else { 3 };
+    if bits_per_sample != 8 || channels != expected_channels {
+        return Err(format!(
+            "image-data: unsupported format ({} bits, {} channels)",
+            bits_per_sample, channels
+        ));
+    }
+    if width <= 0 || height <= 0 || rowstride < width * channels {
+        return Err("image-data: invalid dimensions".to_string());
+    }
+
+    let (width, height, rowstride, channels) = (
+        width as usize,
+        height as usize,
+        rowstride as usize,
+        channels as usize,
+    );
+    if data.len() < rowstride * (height - 1) + width * channels {
+        return Err("image-data: truncated data".to_string());
+    }
+
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for row in 0..height {
+        let start = row * rowstride;
+        rgba.extend(to_rgba(
+            &data[start..start + width * channels],
+            channels,
+            width,
+        ));
+    }
+
+    Ok(RgbaImage {
+        width: width as u32,
+        height: height as u32,
+        data: rgba,
+    })
+}
+
+fn check_icon_file(path: &Path, metadata: &fs::Metadata) -> Result<(), String> {
+    if !metadata.is_file() {
+        return Err(format!("{} is not a regular file", path.display()));
+    }
+    if metadata.len() > MAX_ICON_FILE_SIZE {
+        return Err(format!("{} is too big", path.display()));
+    }
+    Ok(())
+}
+
+// The path comes from the app, so it may point to a FIFO or a device that
+// would block us on open. Check it before opening it, and open it without
+// blocking in case it gets replaced in between.
+fn load_png(path: &Path) -> Result<RgbaImage, String> {
+    check_icon_file(path, &fs::metadata(path).map_err(|err| err.to_string())?)?;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|err| err.to_string())?;
+    check_icon_file(path, &file.metadata().map_err(|err| err.to_string())?)?;
+
+    let mut limits = png::Limits::default();
+    limits.pixels = 1024 * 1024;
+    let decoder = png::Decoder::new_with_limits(file, limits);
+    let (info, mut reader) = decoder.read_info().map_err(|err| err.to_string())?;
+    let mut buf = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buf).map_err(|err| err.to_string())?;
+
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        _ => return Err(format!("{}: unsupported color type", path.display())),
+    };
+
+    let width = info.width as usize;
+    let mut rgba = Vec::with_capacity(width * info.height as usize * 4);
+    for line in buf.chunks(info.line_size) {
+        rgba.extend(to_rgba(line, channels, width));
+    }
+
+    Ok(RgbaImage {
+        width: info.width,
+        height: info.height,
+        data: rgba,
+    })
+}
+
+fn data_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+
+    if let Ok(home) = env::var("HOME") {
+        dirs.push(PathBuf::from(home).join(".local/share"));
+    }
+
+    let xdg_data_dirs = match env::var("XDG_DATA_DIRS") {
+        Ok(d) => d,
+        Err(_) => "/usr/local/share:/usr/share".to_string(),
+    };
+    for dir in xdg_data_dirs.split(':').filter(|d| !d.is_empty()) {
+        dirs.push(PathBuf::from(dir));
+    }
+
+    dirs
+}
+
+// Looks for a PNG version of the icon in the icon themes present in the
+// guest. SVG icons are skipped, as we don't have a way to rasterize them.
+fn lookup_theme_icon(name: &str) -> Option<PathBuf> {
+    let file_name = format!("{}.png", name);
+    let dirs = data_dirs();
+
+    for theme in ICON_THEMES.iter() {
+        for size in ICON_SIZES.iter() {
+            for dir in dirs.iter() {
+                let size_dir = dir.join("icons").join(theme).join(size);
+                let contexts = match fs::read_dir(&size_dir) {
+                    Ok(contexts) => contexts,
+                    Err(_) => continue,
+                };
+                for context in contexts.filter_map(|c| c.ok()) {
+                    let candidate = context.path().join(&file_name);
+                    if candidate.is_file() {
+                        return Some(candidate);
+                    }
+                }
+            }
+        }
+    }
+
+    dirs.iter()
+        .map(|dir| dir.join("pixmaps").join(&file_name))
+        .find(|candidate| candidate.is_file())
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Returns the local path a "file://" URI refers to, with its
+// percent-encoded bytes decoded.
+fn file_uri_path(uri: &str) -> Result<PathBuf, String> {
+    let path = uri["file://".len()..].trim_start_matches("localhost");
+    if !path.starts_with('/') {
+        return Err(format!("{} is not a local file URI", uri));
+    }
+
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+    while let Some(b) = iter.next() {
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        match (
+            iter.next().and_then(hex_value),
+            iter.next().and_then(hex_value),
+        ) {
+            (Some(hi), Some(lo)) => bytes.push((hi << 4) | lo),
+            _ => return Err(format!("{} has an invalid escape", uri)),
+        }
+    }
+
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+// Resolves an icon reference, which can be a "file://" URI, an absolute
+// path or the name of an icon in the theme.
+fn load_icon(icon: &str) -> Result<RgbaImage, String> {
+    let path = if icon.starts_with("file://") {
+        file_uri_path(icon)?
+    } else if icon.starts_with('/') {
+        PathBuf::from(icon)
+    } else {
+        lookup_theme_icon(icon).ok_or(format!("can't find icon {} in theme", icon))?
+    };
+
+    load_png(&path)
+}
+
+/// Returns the icon to be shown with a notification, encoded as a PNG image
+/// no bigger than MAX_ICON_SIZE on either side. Follows the priorities
+/// defined by the Desktop Notifications Specification: "image-data" hint,
+/// "image-path" hint and, finally, "app_icon".
+pub fn notification_icon(
+    app_icon: &str,
+    hints: &HashMap<&str, Variant<Box<dyn RefArg>>>,
+) -> Option<Vec<u8>> {
+    let image = ["image-data", "image_data", "icon_data"]
+        .iter()
+        .filter_map(|hint| hints.get(hint))
+        .map(|v| decode_image_data(&*v.0))
+        .next()
+        .or_else(|| {
+            ["image-path", "image_path"]
+                .iter()
+                .filter_map(|hint| hints.get(hint))
+                .filter_map(|v| v.0.as_str())
+                .map(load_icon)
+                .next()
+        })
+        .or_else(|| {
+            if app_icon.is_empty() {
+                None
+            } else {
+                Some(load_icon(app_icon))
+            }
+        })?;
+
+    match image.and_then(|image| image.bounded().to_png()) {
+        Ok(png) => Some(png),
+        Err(err) => {
+            debug!("can't load notification icon: {}", err);
+            None
+        }
+    }
+}
+
+// Decodes the biggest of the images in an "a(iiay)" StatusNotifierItem
+// pixmap property, with pixels in ARGB32 format and network byte order.
+fn decode_argb_pixmaps(arg: &dyn RefArg) -> Result<RgbaImage, String> {
+    let pixmaps = arg.as_iter().ok_or("pixmap: not an array".to_string())?;
+
+    let mut best: Option<RgbaImage> = None;
+    for pixmap in pixmaps {
+        let mut fields = match pixmap.as_iter() {
+            Some(fields) => fields,
+            None => continue,
+        };
+        let width = fields.next().and_then(|f| f.as_i64()).unwrap_or(0);
+        let height = fields.next().and_then(|f| f.as_i64()).unwrap_or(0);
+        if width <= 0 || height <= 0 || width > 1024 || height > 1024 {
+            continue;
+        }
+        if best.as_ref().map_or(false, |b| b.width as i64 >= width) {
+            continue;
+        }
+
+        let pixels = (width * height) as usize;
+        let data: Vec<u8> = match fields.next().and_then(|f| f.as_iter()) {
+            Some(data) => data.filter_map(|b| b.as_u64().map(|b| b as u8)).collect(),
+            None => continue,
+        };
+        if data.len() < pixels * 4 {
+            continue;
+        }
+
+        let mut rgba = Vec::with_capacity(pixels * 4);
+        for px in data.chunks(4).take(pixels) {
+            rgba.extend_from_slice(&[px[1], px[2], px[3], px[0]]);
+        }
+        best = Some(RgbaImage {
+            width: width as u32,
+            height: height as u32,
+            data: rgba,
+        });
+    }
+
+    best.ok_or("pixmap: no valid images".to_string())
+}
+
+/// Returns the icon of a StatusNotifierItem, encoded as a PNG image no
+/// bigger than MAX_ICON_SIZE on either side. Icons from the theme are
+/// preferred over pixmaps, as the Specification suggests.
+pub fn status_notifier_icon(icon_name: &str, pixmaps: Option<&dyn RefArg>) -> Option<Vec<u8>> {
+    let image = match (icon_name, pixmaps) {
+        ("", Some(pixmaps)) => decode_argb_pixmaps(pixmaps),
+        ("", None) => return None,
+        (name, Some(pixmaps)) => load_icon(name).or_else(|_| decode_argb_pixmaps(pixmaps)),
+        (name, None) => load_icon(name),
+    };
+
+    match image.and_then(|image| image.bounded().to_png()) {
+        Ok(png) => Some(png),
+        Err(err) => {
+            debug!("can't load status notifier icon: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_uri_plain() {
+        assert_eq!(
+            file_uri_path("file:///usr/share/icons/a.png").unwrap(),
+            PathBuf::from("/usr/share/icons/a.png")
+        );
+    }
+
+    #[test]
+    fn file_uri_localhost() {
+        assert_eq!(
+            file_uri_path("file://localhost/tmp/a.png").unwrap(),
+            PathBuf::from("/tmp/a.png")
+        );
+    }
+
+    #[test]
+    fn file_uri_percent_encoded() {
+        assert_eq!(
+            file_uri_path("file:///tmp/my%20icon%2a.png").unwrap(),
+            PathBuf::from("/tmp/my icon*.png")
+        );
+    }
+
+    #[test]
+    fn file_uri_invalid_escape() {
+        assert!(file_uri_path("file:///tmp/a%2.png").is_err());
+        assert!(file_uri_path("file:///tmp/a%zz.png").is_err());
+    }
+
+    #[test]
+    fn file_uri_remote_host() {
+        assert!(file_uri_path("file://example.com/tmp/a.png").is_err());
+    }
+
+    #[test]
+    fn fifo_is_rejected() {
+        let dir = env::temp_dir().join(format!("flatkvm-icon-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let fifo = dir.join("icon.png");
+        let c_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        // Safe because c_path is a valid NUL-terminated string.
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let result = load_png(&fifo);
+        fs::remove_file(&fifo).unwrap();
+        fs::remove_dir(&dir).unwrap();
+        assert!(result.unwrap_err().contains("not a regular file"));
+    }
+}
@@ -15,15 +15,14 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use std::env;
-use std::fs::create_dir_all;
-use std::fs::File;
+use std::fs::{create_dir_all, remove_dir, File};
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::process::{exit, Child, Command};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
-use std::sync::mpsc::{channel, Sender};
 use std::sync::Arc;
-use std::thread;
+use std::time::{Duration, Instant};
 
 use clap::{crate_authors, crate_version, App, Arg};
 use log::{debug, error, info};
@@ -35,16 +34,61 @@ use flatkvm_qemu::agent::*;
 use flatkvm_qemu::clipboard::*;
 use flatkvm_qemu::runner::{QemuSharedDir, QemuSharedDirType};
 
+use crate::event_channel::{EventReceiver, EventSender};
+
+mod apps;
 mod dbus_listener;
+mod devices;
+mod display;
+mod event_channel;
+mod health;
+mod icon;
 mod message;
+mod notification_filter;
+mod portal;
+mod power;
+mod reactor;
+mod screensaver;
+mod status_notifier;
 mod udevmon;
 
-fn do_mount_request(agent: &mut AgentGuest, dir: QemuSharedDir) -> Result<(), String> {
-    let homedir = match env::var("HOME") {
+fn home_dir() -> String {
+    match env::var("HOME") {
         Ok(home) => home,
         Err(_) => "/home/flatkvm".to_string(),
+    }
+}
+
+fn mount_9p(tag: &str, target: &str) -> Result<i32, String> {
+    let argsline = format!(
+        "mount -t 9p -o trans=virtio,version=9p2000.L {} {}",
+        tag, target
+    );
+    let args = match split(&argsline) {
+        Some(args) => args,
+        None => return Err("can't format arguments".to_string()),
     };
 
+    let exit_status = Command::new("sudo")
+        .args(args)
+        .status()
+        .map_err(|err| err.to_string())?;
+
+    let exit_code = match exit_status.code() {
+        Some(code) => code,
+        None => -1,
+    };
+
+    Ok(exit_code)
+}
+
+fn do_mount_request(
+    agent: &mut AgentGuest,
+    mounts: &mut Vec<String>,
+    dir: QemuSharedDir,
+) -> Result<(), String> {
+    let homedir = home_dir();
+
     let target = match dir.dir_type {
         QemuSharedDirType::FlatpakSystemDir => "/var/lib/flatpak".to_string(),
         QemuSharedDirType::FlatpakUserDir => {
@@ -69,35 +113,107 @@ fn do_mount_request(agent: &mut AgentGuest, dir: QemuSharedDir) -> Result<(), St
         }
     };
 
-    let argsline = format!(
-        "mount -t 9p -o trans=virtio,version=9p2000.L {} {}",
-        dir.tag, target
-    );
-    let args = match split(&argsline) {
-        Some(args) => args,
-        None => return Err("can't format arguments".to_string()),
-    };
+    let exit_code = mount_9p(&dir.tag, &target)?;
+    if exit_code == 0 {
+        mounts.push(target);
+    }
+    agent.send_ack(exit_code)?;
+    Ok(())
+}
 
+fn file_uri(path: &str) -> String {
+    let mut uri = "file://".to_string();
+    for b in path.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(b as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+fn runtime_dir() -> String {
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) => dir,
+        // Safe because getuid() doesn't touch our memory.
+        Err(_) => format!("/run/user/{}", unsafe { libc::getuid() }),
+    }
+}
+
+fn umount(target: &str) -> Result<i32, String> {
     let exit_status = Command::new("sudo")
-        .args(args)
+        .args(&["umount", target])
         .status()
         .map_err(|err| err.to_string())?;
 
-    let exit_code = match exit_status.code() {
-        Some(code) => code,
-        None => -1,
-    };
+    Ok(exit_status.code().unwrap_or(-1))
+}
 
-    agent.send_ack(exit_code)?;
-    Ok(())
+// Mounts the directory the Host has shared with the file chosen by the
+// user, returning the mountpoint and the URI of the file. It goes in the
+// app's runtime directory, which is visible from its sandbox but, unlike
+// its data directory, isn't shared back with the Host.
+fn do_file_chooser_mount(
+    dir: QemuSharedDir,
+    id: u32,
+    file_name: &str,
+) -> Result<(String, String), String> {
+    if file_name.is_empty() || file_name.contains('/') || file_name == "." || file_name == ".." {
+        return Err(format!("invalid file name: {}", file_name));
+    }
+
+    let target = format!(
+        "{}/app/{}/flatkvm-files/{}",
+        runtime_dir(),
+        dir.app_name,
+        id
+    );
+    create_dir_all(&target).map_err(|err| err.to_string())?;
+
+    match mount_9p(&dir.tag, &target)? {
+        0 => {
+            let uri = file_uri(&format!("{}/{}", target, file_name));
+            Ok((target, uri))
+        }
+        code => {
+            let _ = remove_dir(&target);
+            Err(format!("mount failed with exit code {}", code))
+        }
+    }
+}
+
+// Unmounts and removes the directories mounted for the files chosen by the
+// user. Those that can't be unmounted are left alone.
+fn release_chosen_files(mounts: &mut Vec<String>, chosen: &mut Vec<String>) {
+    for target in chosen.drain(..) {
+        match umount(&target) {
+            Ok(0) => (),
+            Ok(code) => {
+                error!("can't unmount {}: exit code {}", target, code);
+                continue;
+            }
+            Err(err) => {
+                error!("can't unmount {}: {}", target, err);
+                continue;
+            }
+        }
+        mounts.retain(|m| *m != target);
+        if let Err(err) = remove_dir(&target) {
+            debug!("can't remove {}: {}", target, err.to_string());
+        }
+    }
 }
 
 fn do_run_request(
     agent: &mut AgentGuest,
-    sender: Sender<message::Message>,
+    apps: &apps::Apps,
+    sender: EventSender<message::Message>,
     rr: AgentRunRequest,
+    scale: f64,
 ) -> Result<(), String> {
-    let mut child = match spawn_app(rr) {
+    let child = match spawn_app(rr, scale) {
         Ok(child) => child,
         Err(err) => {
             agent.send_ack(-1)?;
@@ -106,17 +222,7 @@ fn do_run_request(
     };
 
     agent.send_ack(0)?;
-
-    thread::spawn(move || {
-        let exit_code = match child.wait() {
-            Ok(exit_status) => match exit_status.code() {
-                Some(code) => code,
-                None => -1,
-            },
-            Err(_) => -1,
-        };
-        sender.send(message::Message::AppExit(exit_code)).unwrap();
-    });
+    apps.track(child, sender);
 
     Ok(())
 }
@@ -140,7 +246,104 @@ fn do_layout_request(agent: &mut AgentGuest, layout: String) -> Result<(), Strin
     Ok(())
 }
 
-fn spawn_app(rr: AgentRunRequest) -> Result<Child, String> {
+fn do_resolution_request(
+    agent: &mut AgentGuest,
+    display_config: &display::DisplayConfig,
+    display_state: &display::HostState,
+    rr: AgentResolutionRequest,
+) -> Result<(), String> {
+    // The Host asks for the size in logical pixels, so take the scale
+    // factor into account to get the size of the mode.
+    let scale = if rr.scale.is_finite() && rr.scale > 0.0 {
+        rr.scale
+    } else {
+        1.0
+    };
+    let width = (rr.width as f64 * scale).round() as u32;
+    let height = (rr.height as f64 * scale).round() as u32;
+
+    // The Host tells the ack of this request from the others by its type,
+    // so failures are reported with it too.
+    let ack = match display::set_resolution(display_config, display_state, width, height) {
+        Ok((width, height)) => AgentResolutionAck {
+            result: 0,
+            width,
+            height,
+        },
+        Err(err) => {
+            error!("can't set resolution: {}", err);
+            // Let the Host know which mode is still in use, if any.
+            let (width, height) = display::current_resolution(display_config).unwrap_or((0, 0));
+            AgentResolutionAck {
+                result: -1,
+                width,
+                height,
+            }
+        }
+    };
+    agent.send_resolution_ack(ack)?;
+
+    Ok(())
+}
+
+fn do_display_layout_request(
+    agent: &mut AgentGuest,
+    display_config: &display::DisplayConfig,
+    display_state: &display::HostState,
+    lr: AgentDisplayLayoutRequest,
+) -> Result<(), String> {
+    let exit_code = match display::apply_layout(display_config, display_state, lr.outputs) {
+        Ok(_) => 0,
+        Err(err) => {
+            error!("can't apply display layout: {}", err);
+            -1
+        }
+    };
+
+    agent.send_ack(exit_code)?;
+    Ok(())
+}
+
+fn do_scale_request(agent: &mut AgentGuest, current: &mut f64, scale: f64) -> Result<(), String> {
+    if !scale.is_finite() || scale <= 0.0 {
+        error!("invalid scale factor: {}", scale);
+        agent.send_ack(-1)?;
+        return Ok(());
+    }
+
+    *current = scale;
+    let exit_code = match display::set_scale(scale) {
+        Ok(_) => 0,
+        Err(err) => {
+            error!("can't set scale factor: {}", err);
+            -1
+        }
+    };
+
+    agent.send_ack(exit_code)?;
+    Ok(())
+}
+
+fn do_status_request(
+    agent: &mut AgentGuest,
+    apps: &apps::Apps,
+    mounts: &[String],
+) -> Result<(), String> {
+    // As with resolution requests, failures are reported with the reply
+    // type the Host is waiting for.
+    let report = match health::status_report(apps, mounts) {
+        Ok(report) => report,
+        Err(err) => {
+            error!("can't collect guest status: {}", err);
+            health::error_report(err)
+        }
+    };
+    agent.send_status_report(report)?;
+
+    Ok(())
+}
+
+fn spawn_app(rr: AgentRunRequest, scale: f64) -> Result<Child, String> {
     let mut args = vec!["run"];
 
     if rr.user {
@@ -178,6 +381,14 @@ fn spawn_app(rr: AgentRunRequest) -> Result<Child, String> {
     // escaping the sandbox to write on $HOME/.mozilla), but shouldn't hurt
     // others, so we use this unconditionally.
     args.push("--persist=.mozilla");
+
+    // Toolkits only read these on startup, so apps already running won't
+    // pick up later changes in the scale factor.
+    let scale_args: Vec<String> = display::scale_env(scale)
+        .iter()
+        .map(|(var, value)| format!("--env={}={}", var, value))
+        .collect();
+    args.extend(scale_args.iter().map(|a| a.as_str()));
     args.push(&rr.app);
 
     debug!("running app with args: {:?}", args);
@@ -190,19 +401,54 @@ fn spawn_app(rr: AgentRunRequest) -> Result<Child, String> {
     Ok(proc)
 }
 
+fn store_remote_clipboard(clipboard: &mut Clipboard, ce: &ClipboardEvent) -> Result<(), String> {
+    match clipboard.store(
+        clipboard.setter.atoms.clipboard,
+        clipboard.setter.atoms.utf8_string,
+        ce.data.as_bytes(),
+    ) {
+        Ok(_) => return Ok(()),
+        Err(err) => debug!(
+            "clipboard store failed, recreating owner: {}",
+            err.to_string()
+        ),
+    }
+
+    // The X11 connection backing the clipboard owner may be gone (i.e. the
+    // X server was restarted), so create a fresh one and try once more.
+    *clipboard = Clipboard::new().map_err(|err| err.to_string())?;
+    clipboard
+        .store(
+            clipboard.setter.atoms.clipboard,
+            clipboard.setter.atoms.utf8_string,
+            ce.data.as_bytes(),
+        )
+        .map_err(|err| err.to_string())
+}
+
+// Relays the messages from the Host to MessageHandler. The socket is shared
+// with the writer used by MessageHandler, so it stays blocking, and we rely
+// on AgentGuest to only read what's already available.
 struct HostListener {
     agent: AgentGuest,
-    sender: Sender<message::Message>,
+    sender: EventSender<message::Message>,
 }
 
 impl HostListener {
-    pub fn new(agent: AgentGuest, sender: Sender<message::Message>) -> HostListener {
+    pub fn new(agent: AgentGuest, sender: EventSender<message::Message>) -> HostListener {
         HostListener { agent, sender }
     }
 
-    pub fn get_and_process_event(&mut self) -> Result<(), String> {
-        let event = self.agent.get_event()?;
+    // Processes all the complete messages received so far, including those
+    // AgentGuest may have buffered during the handshake.
+    fn process_available(&mut self) -> Result<(), String> {
+        for event in self.agent.try_get_events()? {
+            self.process_event(event)?;
+        }
+        Ok(())
+    }
 
+    fn process_event(&self, event: AgentMessage) -> Result<(), String> {
         match event {
             AgentMessage::AgentMountRequest(mr) => {
                 debug!("Agentmessage::Message::AgentMountRequest");
@@ -220,6 +466,34 @@ impl HostListener {
                     .send(message::Message::LayoutRequest(lr.layout))
                     .unwrap();
             }
+            AgentMessage::AgentResolutionRequest(rr) => {
+                debug!("AgentResolutionRequest");
+                self.sender
+                    .send(message::Message::ResolutionRequest(rr))
+                    .unwrap();
+            }
+            AgentMessage::AgentDisplayLayoutRequest(lr) => {
+                debug!("AgentDisplayLayoutRequest");
+                self.sender
+                    .send(message::Message::DisplayLayoutRequest(lr))
+                    .unwrap();
+            }
+            AgentMessage::AgentScaleRequest(sr) => {
+                debug!("AgentScaleRequest");
+                self.sender
+                    .send(message::Message::ScaleRequest(sr))
+                    .unwrap();
+            }
+            AgentMessage::AgentPowerRequest(pr) => {
+                debug!("AgentPowerRequest");
+                self.sender
+                    .send(message::Message::PowerRequest(pr))
+                    .unwrap();
+            }
+            AgentMessage::AgentStatusRequest => {
+                debug!("AgentStatusRequest");
+                self.sender.send(message::Message::StatusRequest).unwrap();
+            }
             AgentMessage::ClipboardEvent(ce) => {
                 debug!("AgentClipboardEvent");
                 self.sender
@@ -232,6 +506,24 @@ impl HostListener {
                     .send(message::Message::DbusNotificationClosed(nc))
                     .unwrap();
             }
+            AgentMessage::AgentFileChooserResponse(fr) => {
+                debug!("AgentFileChooserResponse");
+                self.sender
+                    .send(message::Message::FileChooserResponse(fr))
+                    .unwrap();
+            }
+            AgentMessage::StatusNotifierActivation(sa) => {
+                debug!("AgentStatusNotifierActivation");
+                self.sender
+                    .send(message::Message::StatusNotifierActivation(sa))
+                    .unwrap();
+            }
+            AgentMessage::DbusNotificationAction(na) => {
+                debug!("AgentDbusNotificationAction");
+                self.sender
+                    .send(message::Message::DbusNotificationAction(na))
+                    .unwrap();
+            }
             _ => return Err("Protocol error".to_string()),
         }
 
@@ -239,163 +531,261 @@ impl HostListener {
     }
 }
 
-fn main() {
-    let homedir = match env::var("HOME") {
-        Ok(home) => home,
-        Err(_) => "/home/flatkvm".to_string(),
-    };
-
-    CombinedLogger::init(vec![WriteLogger::new(
-        LevelFilter::Debug,
-        Config::default(),
-        File::create(format!("{}/flatkvm-agent.log", homedir)).unwrap(),
-    )])
-    .unwrap();
-
-    let cmd_args = App::new("flatkvm-agent")
-        .version(crate_version!())
-        .author(crate_authors!())
-        .about("FlatKvm Agent")
-        .arg(
-            Arg::with_name("vsock")
-                .short("v")
-                .long("vsock")
-                .help("vsock port")
-                .takes_value(true)
-                .required(true),
-        )
-        .get_matches();
-
-    let vsock_path = cmd_args
-        .value_of("vsock")
-        .map(|s| PathBuf::from(s))
-        .unwrap();
-
-    let mut agent = match AgentGuest::new(vsock_path) {
-        Ok(agent) => agent,
-        Err(err) => {
-            error!("error creating agent: {}", err.to_string());
-            exit(-1);
-        }
-    };
-
-    let mut agent_writer = agent.try_clone().unwrap();
+impl reactor::Source for HostListener {
+    fn fds(&self) -> Vec<libc::pollfd> {
+        vec![libc::pollfd {
+            fd: self.agent.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }]
+    }
 
-    info!("Doing handshake");
-    match agent.do_handshake(crate_version!()) {
-        Ok(_) => (),
-        Err(err) => {
-            error!("error in handshake with agent: {}", err.to_string());
-            exit(-1);
-        }
-    };
-    info!("Handshake done");
+    fn handle(&mut self, _pfd: &libc::pollfd) -> Result<(), String> {
+        self.process_available()
+            .map_err(|err| format!("error processing host events: {}", err))
+    }
+}
 
-    let (common_sender, common_receiver) = channel();
-    let (clipboard_sender, clipboard_receiver) = channel();
+// Relays the changes in the local clipboard to the Host.
+struct ClipboardMonitor {
+    listener: ClipboardListener,
+    sender: EventSender<message::Message>,
+}
 
-    // Spawn a thread to listen for clipboard events.
-    let cb_used_flag = Arc::new(AtomicBool::new(false));
-    ClipboardListener::new(clipboard_sender.clone(), cb_used_flag.clone()).spawn_thread();
-
-    // Translate clipboard messages into our own kind.
-    let sender = common_sender.clone();
-    thread::spawn(move || loop {
-        for msg in &clipboard_receiver {
-            match msg {
-                ClipboardMessage::ClipboardEvent(ce) => {
-                    sender
-                        .send(message::Message::LocalClipboardEvent(ce))
-                        .unwrap();
-                }
-            }
-        }
-    });
-
-    // Spawn a thread to listen for udev events.
-    // We use this to detect video resolution changes.
-    thread::spawn(move || loop {
-        match udevmon::monitor() {
-            Ok(()) => (),
-            Err(err) => debug!("udev error: {}", err.to_string()),
-        }
-    });
+impl reactor::Source for ClipboardMonitor {
+    fn fds(&self) -> Vec<libc::pollfd> {
+        vec![libc::pollfd {
+            fd: self.listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }]
+    }
 
-    // Spawn a thread waiting for messages coming from the Host.
-    let mut host_listener = HostListener::new(agent, common_sender.clone());
-    thread::spawn(move || loop {
-        info!("Waiting for events from Host");
-        match host_listener.get_and_process_event() {
-            Ok(_) => (),
-            Err(err) => {
-                error!("error processing host events: {}", err.to_string());
-                exit(-1);
-            }
+    fn handle(&mut self, _pfd: &libc::pollfd) -> Result<(), String> {
+        let events = self
+            .listener
+            .try_get_events()
+            .map_err(|err| format!("error processing clipboard events: {}", err))?;
+        for ce in events {
+            self.sender
+                .send(message::Message::LocalClipboardEvent(ce))
+                .unwrap();
         }
-    });
-
-    let dbus_sender = common_sender.clone();
-    let (dbus_nc_sender, dbus_nc_receiver) = channel();
-    thread::spawn(move || {
-        dbus_listener::handle_dbus_notifications(dbus_sender, dbus_nc_receiver);
-    });
+        Ok(())
+    }
+}
 
-    // Create another clipboard instance to store values.
-    let clipboard = Clipboard::new().unwrap();
+// Processes the messages coming from the other sources and the threads
+// waiting for the apps to exit.
+struct MessageHandler {
+    receiver: EventReceiver<message::Message>,
+    sender: EventSender<message::Message>,
+    agent: AgentGuest,
+    clipboard: Clipboard,
+    cb_used_flag: Arc<AtomicBool>,
+    display_config: display::DisplayConfig,
+    display_state: display::HostState,
+    scale: f64,
+    dbus_signal_sender: EventSender<dbus_listener::DbusSignal>,
+    portal: Arc<portal::Portal>,
+    apps: apps::Apps,
+    // Targets of the shared directories mounted, in mount order.
+    mounts: Vec<String>,
+    // Those of the above mounted for the files chosen by the user, to be
+    // released once the apps are gone.
+    chosen_files: Vec<String>,
+    // If set, how long to wait before powering off once all apps have
+    // exited.
+    exit_linger: Option<Duration>,
+    poweroff_at: Option<Instant>,
+    // Whether we're already shutting down, rebooting or suspending.
+    power_pending: bool,
+}
 
-    // Process events coming from spawned threads.
-    for msg in common_receiver {
+impl MessageHandler {
+    fn process(&mut self, msg: message::Message) {
         match msg {
             message::Message::LocalClipboardEvent(ce) => {
                 debug!("Clipboard event");
-                agent_writer.send_clipboard_event(ce).unwrap();
+                self.agent.send_clipboard_event(ce).unwrap();
             }
             message::Message::RemoteClipboardEvent(ce) => {
                 debug!("RemoteClipboard");
-                cb_used_flag.store(true, Ordering::Relaxed);
-                match clipboard.store(
-                    clipboard.setter.atoms.clipboard,
-                    clipboard.setter.atoms.utf8_string,
-                    ce.data.as_bytes(),
-                ) {
+                self.cb_used_flag.store(true, Ordering::Relaxed);
+                match store_remote_clipboard(&mut self.clipboard, &ce) {
                     Ok(_) => (),
                     Err(err) => {
-                        error!("can't store value in clipboard: {}", err.to_string());
-                        exit(-1);
+                        error!("can't store value in clipboard: {}", err);
+                        if let Err(err) = self.agent.send_clipboard_failure(ce) {
+                            error!("can't report clipboard failure: {}", err.to_string());
+                        }
                     }
                 }
             }
             message::Message::DbusNotification(dn) => {
                 debug!("DbusNotification");
-                agent_writer.send_dbus_notification(dn).unwrap();
+                self.agent.send_dbus_notification(dn).unwrap();
+            }
+            message::Message::DbusNotificationClose(nc) => {
+                debug!("DbusNotificationClose: {}", nc.id);
+                self.agent.send_dbus_notification_close(nc).unwrap();
             }
             message::Message::DbusNotificationClosed(nc) => {
                 debug!("DbusNotificationClosed: {}", nc.id);
-                dbus_nc_sender.send(nc).unwrap();
-                /*
-                let path: Path<'static> = format!("/org/freedesktop/Notifications").into();
-                let sig = OrgFreedesktopNotificationsNotificationClosed {
-                    id: nc.id,
-                    reason: nc.reason,
+                self.dbus_signal_sender
+                    .send(dbus_listener::DbusSignal::NotificationClosed(nc))
+                    .unwrap();
+            }
+            message::Message::DbusNotificationAction(na) => {
+                debug!("DbusNotificationAction: {} {}", na.id, na.action_key);
+                self.dbus_signal_sender
+                    .send(dbus_listener::DbusSignal::ActionInvoked(na))
+                    .unwrap();
+            }
+            message::Message::DbusNotificationStatus(st) => {
+                debug!("DbusNotificationStatus: {}", st.available);
+                self.agent.send_dbus_notification_status(st).unwrap();
+            }
+            message::Message::OpenUri(uri) => {
+                debug!("OpenUri");
+                self.agent.send_open_uri(uri).unwrap();
+            }
+            message::Message::FileChooserRequest(fr) => {
+                debug!("FileChooserRequest: {}", fr.id);
+                self.agent.send_file_chooser_request(fr).unwrap();
+            }
+            message::Message::FileChooserResponse(fr) => {
+                debug!("FileChooserResponse: {}", fr.id);
+                // Don't mount anything for a request no one is waiting for,
+                // as it would stay there until all apps are gone.
+                if !self.portal.is_pending(fr.id) {
+                    error!("ignoring response to unknown file chooser request");
+                    return;
+                }
+                let event = match fr.shared_dir {
+                    Some(dir) => match do_file_chooser_mount(dir, fr.id, &fr.file_name) {
+                        Ok((target, uri)) => {
+                            self.mounts.push(target.clone());
+                            self.chosen_files.push(target);
+                            portal::PortalEvent::FileChosen(fr.id, Some(uri))
+                        }
+                        Err(err) => {
+                            error!("error mounting chosen file: {}", err);
+                            portal::PortalEvent::FileChooserFailed(fr.id)
+                        }
+                    },
+                    None => portal::PortalEvent::FileChosen(fr.id, None),
                 };
-                dbus_conn
-                    .send(sig.to_emit_message(&path))
-                    .expect("sending DBus signal failed");
-                */
+                self.dbus_signal_sender
+                    .send(dbus_listener::DbusSignal::Portal(event))
+                    .unwrap();
+            }
+            message::Message::StatusNotifierItem(item) => {
+                debug!("StatusNotifierItem: {}", item.id);
+                self.agent.send_status_notifier_item(item).unwrap();
+            }
+            message::Message::StatusNotifierItemRemoved(item) => {
+                debug!("StatusNotifierItemRemoved: {}", item.id);
+                self.agent.send_status_notifier_item_removed(item).unwrap();
+            }
+            message::Message::StatusNotifierActivation(sa) => {
+                debug!("StatusNotifierActivation: {}", sa.id);
+                self.dbus_signal_sender
+                    .send(dbus_listener::DbusSignal::StatusNotifier(sa))
+                    .unwrap();
+            }
+            message::Message::ScreenSaverInhibit(si) => {
+                debug!("ScreenSaverInhibit: {}", si.cookie);
+                self.agent.send_screensaver_inhibit(si).unwrap();
+            }
+            message::Message::ScreenSaverUnInhibit(su) => {
+                debug!("ScreenSaverUnInhibit: {}", su.cookie);
+                self.agent.send_screensaver_uninhibit(su).unwrap();
+            }
+            message::Message::DeviceEvent(de) => {
+                debug!("DeviceEvent: {}", de.id);
+                self.agent.send_device_event(de).unwrap();
+            }
+            message::Message::PowerRequest(pr) => {
+                debug!("PowerRequest: {:?}", pr.action);
+                if self.power_pending {
+                    error!("power action already in progress, rejecting request");
+                    self.agent
+                        .send_power_ack(AgentPowerAck {
+                            phase: power::first_phase(&pr.action),
+                            action: pr.action,
+                            result: -1,
+                        })
+                        .unwrap();
+                } else {
+                    // The Host is taking care of it now.
+                    self.poweroff_at = None;
+                    self.power_pending = true;
+                    power::handle_request(
+                        pr.action,
+                        self.apps.clone(),
+                        self.mounts.clone(),
+                        self.sender.clone(),
+                    );
+                }
+            }
+            message::Message::PowerAck(pa) => {
+                debug!("PowerAck: {:?} {}", pa.phase, pa.result);
+                // We're back from a suspend, or the power action failed, so
+                // the Host may ask for another one.
+                if pa.phase == PowerPhase::PowerAction
+                    && (pa.action == PowerAction::Suspend || pa.result != 0)
+                {
+                    self.power_pending = false;
+                }
+                self.agent.send_power_ack(pa).unwrap();
+            }
+            message::Message::PowerOffResult(result) => {
+                debug!("PowerOffResult: {}", result);
+                // Only clear it on failure, so nothing else gets started
+                // while the guest is going down.
+                if result != 0 {
+                    error!("can't power off: exit code {}", result);
+                    self.power_pending = false;
+                }
+            }
+            message::Message::StatusRequest => {
+                debug!("StatusRequest");
+                match do_status_request(&mut self.agent, &self.apps, &self.mounts) {
+                    Ok(_) => (),
+                    Err(err) => {
+                        error!("error servicing status request: {}", err.to_string());
+                        exit(-1);
+                    }
+                }
             }
             message::Message::AppExit(ec) => {
                 debug!("AppExit");
-                match agent_writer.send_exit_code(ec) {
+                match self.agent.send_exit_code(ec) {
                     Ok(_) => (),
                     Err(err) => {
                         error!("can't send exit code: {}", err.to_string());
                         exit(-1);
                     }
                 }
+                // The chosen files may be in use by any of the apps, so
+                // release them once none is left. If we're powering off,
+                // they're unmounted along with the rest.
+                if self.apps.is_empty() && !self.power_pending {
+                    release_chosen_files(&mut self.mounts, &mut self.chosen_files);
+                }
+                // Apps exiting because we're already powering off must not
+                // schedule another power off.
+                if let Some(linger) = self.exit_linger {
+                    if self.apps.is_empty() && !self.power_pending {
+                        debug!("no apps left, powering off in {:?}", linger);
+                        self.poweroff_at = Some(Instant::now() + linger);
+                    }
+                }
             }
             message::Message::MountRequest(dir) => {
                 debug!("MountRequest");
-                match do_mount_request(&mut agent_writer, dir) {
+                match do_mount_request(&mut self.agent, &mut self.mounts, dir) {
                     Ok(_) => (),
                     Err(err) => {
                         error!("error servicing mount request: {}", err.to_string());
@@ -405,7 +795,17 @@ fn main() {
             }
             message::Message::RunRequest(rr) => {
                 debug!("RunRequest");
-                match do_run_request(&mut agent_writer, common_sender.clone(), rr) {
+                if let Some(secs) = rr.exit_linger {
+                    self.exit_linger = Some(Duration::from_secs(secs.into()));
+                }
+                self.poweroff_at = None;
+                match do_run_request(
+                    &mut self.agent,
+                    &self.apps,
+                    self.sender.clone(),
+                    rr,
+                    self.scale,
+                ) {
                     Ok(_) => (),
                     Err(err) => {
                         error!("error sevicing run request: {}", err.to_string());
@@ -415,7 +815,7 @@ fn main() {
             }
             message::Message::LayoutRequest(layout) => {
                 debug!("LayoutRequest");
-                match do_layout_request(&mut agent_writer, layout) {
+                match do_layout_request(&mut self.agent, layout) {
                     Ok(_) => (),
                     Err(err) => {
                         error!("error servicing layout request: {}", err.to_string());
@@ -423,8 +823,255 @@ fn main() {
                     }
                 }
             }
+            message::Message::ResolutionRequest(rr) => {
+                debug!("ResolutionRequest: {}x{}", rr.width, rr.height);
+                match do_resolution_request(
+                    &mut self.agent,
+                    &self.display_config,
+                    &self.display_state,
+                    rr,
+                ) {
+                    Ok(_) => (),
+                    Err(err) => {
+                        error!("error servicing resolution request: {}", err.to_string());
+                        exit(-1);
+                    }
+                }
+            }
+            message::Message::DisplayLayoutRequest(lr) => {
+                debug!("DisplayLayoutRequest: {} outputs", lr.outputs.len());
+                match do_display_layout_request(
+                    &mut self.agent,
+                    &self.display_config,
+                    &self.display_state,
+                    lr,
+                ) {
+                    Ok(_) => (),
+                    Err(err) => {
+                        error!(
+                            "error servicing display layout request: {}",
+                            err.to_string()
+                        );
+                        exit(-1);
+                    }
+                }
+            }
+            message::Message::ScaleRequest(sr) => {
+                debug!("ScaleRequest: {}", sr.scale);
+                match do_scale_request(&mut self.agent, &mut self.scale, sr.scale) {
+                    Ok(_) => (),
+                    Err(err) => {
+                        error!("error servicing scale request: {}", err.to_string());
+                        exit(-1);
+                    }
+                }
+            }
         }
     }
+}
+
+impl reactor::Source for MessageHandler {
+    fn fds(&self) -> Vec<libc::pollfd> {
+        vec![libc::pollfd {
+            fd: self.receiver.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }]
+    }
 
-    debug!("Ending!");
+    fn handle(&mut self, _pfd: &libc::pollfd) -> Result<(), String> {
+        let msgs: Vec<message::Message> = self.receiver.try_iter().collect();
+        for msg in msgs {
+            self.process(msg);
+        }
+        Ok(())
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        if self.power_pending {
+            return None;
+        }
+        self.poweroff_at
+    }
+
+    fn handle_deadline(&mut self) -> Result<(), String> {
+        self.poweroff_at = None;
+        self.power_pending = true;
+        power::power_off(self.mounts.clone(), self.sender.clone());
+        Ok(())
+    }
+}
+
+fn main() {
+    let homedir = match env::var("HOME") {
+        Ok(home) => home,
+        Err(_) => "/home/flatkvm".to_string(),
+    };
+
+    CombinedLogger::init(vec![WriteLogger::new(
+        LevelFilter::Debug,
+        Config::default(),
+        File::create(format!("{}/flatkvm-agent.log", homedir)).unwrap(),
+    )])
+    .unwrap();
+
+    let cmd_args = App::new("flatkvm-agent")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("FlatKvm Agent")
+        .arg(
+            Arg::with_name("vsock")
+                .short("v")
+                .long("vsock")
+                .help("vsock port")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("display-policy")
+                .long("display-policy")
+                .help("how to configure outputs on display changes")
+                .takes_value(true)
+                .possible_values(&["auto", "preferred", "none"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("exit-linger")
+                .long("exit-linger")
+                .help("power off this many seconds after the last app exits")
+                .takes_value(true)
+                .validator(|v| {
+                    v.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| format!("invalid number of seconds: {}", v))
+                }),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .help("output to be managed (default: all)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .get_matches();
+
+    let vsock_path = cmd_args
+        .value_of("vsock")
+        .map(|s| PathBuf::from(s))
+        .unwrap();
+
+    let display_config = display::DisplayConfig {
+        policy: cmd_args
+            .value_of("display-policy")
+            .unwrap()
+            .parse()
+            .unwrap(),
+        outputs: cmd_args
+            .values_of("output")
+            .map_or(Vec::new(), |v| v.map(|s| s.to_string()).collect()),
+    };
+
+    let exit_linger = cmd_args
+        .value_of("exit-linger")
+        .map(|s| Duration::from_secs(s.parse().unwrap()));
+
+    let mut agent = match AgentGuest::new(vsock_path) {
+        Ok(agent) => agent,
+        Err(err) => {
+            error!("error creating agent: {}", err.to_string());
+            exit(-1);
+        }
+    };
+
+    let mut agent_writer = agent.try_clone().unwrap();
+
+    info!("Doing handshake");
+    let handshake = match agent.do_handshake(crate_version!()) {
+        Ok(hs) => hs,
+        Err(err) => {
+            error!("error in handshake with agent: {}", err.to_string());
+            exit(-1);
+        }
+    };
+    info!("Handshake done");
+
+    let scale = if handshake.scale_factor.is_finite() && handshake.scale_factor > 0.0 {
+        handshake.scale_factor
+    } else {
+        1.0
+    };
+    if let Err(err) = display::set_scale(scale) {
+        error!("can't set initial scale factor: {}", err);
+    }
+
+    let (common_sender, common_receiver) = event_channel::channel().unwrap();
+
+    let cb_used_flag = Arc::new(AtomicBool::new(false));
+    let clipboard_monitor = ClipboardMonitor {
+        listener: ClipboardListener::new(cb_used_flag.clone()).unwrap(),
+        sender: common_sender.clone(),
+    };
+
+    // Listen for udev events. We use this to detect video resolution
+    // changes, and to let the Host know about hotplugged devices.
+    let mut udev_monitor = udevmon::UdevMonitor::new();
+    let display_state = display::HostState::default();
+    display::add_udev_handlers(
+        &mut udev_monitor,
+        display_config.clone(),
+        display_state.clone(),
+    );
+    devices::add_udev_handlers(&mut udev_monitor, common_sender.clone());
+    udev_monitor.start();
+
+    let mut host_listener = HostListener::new(agent, common_sender.clone());
+    // Messages that arrived along with the handshake won't wake up the
+    // reactor, so take care of them now.
+    if let Err(err) = host_listener.process_available() {
+        error!("error processing host events: {}", err);
+        exit(-1);
+    }
+
+    let (dbus_signal_sender, dbus_signal_receiver) = event_channel::channel().unwrap();
+    let mut dbus = dbus_listener::DbusListener::new(
+        common_sender.clone(),
+        dbus_signal_receiver,
+        handshake.notification_capabilities,
+    );
+    dbus.connect();
+    let portal = dbus.portal();
+
+    // Create another clipboard instance to store values.
+    let clipboard = Clipboard::new().unwrap();
+
+    let mut reactor = reactor::Reactor::new();
+    reactor.add_source(Box::new(udev_monitor));
+    reactor.add_source(Box::new(host_listener));
+    reactor.add_source(Box::new(clipboard_monitor));
+    reactor.add_source(Box::new(dbus));
+    reactor.add_source(Box::new(MessageHandler {
+        receiver: common_receiver,
+        sender: common_sender,
+        agent: agent_writer,
+        clipboard,
+        cb_used_flag,
+        display_config,
+        display_state,
+        scale,
+        dbus_signal_sender,
+        portal,
+        apps: apps::Apps::new(),
+        mounts: Vec::new(),
+        chosen_files: Vec::new(),
+        exit_linger,
+        poweroff_at: None,
+        power_pending: false,
+    }));
+
+    // Process the events from all sources.
+    if let Err(err) = reactor.run() {
+        error!("{}", err);
+        exit(-1);
+    }
 }
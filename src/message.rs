@@ -14,18 +14,48 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use flatkvm_qemu::agent::AgentRunRequest;
+use flatkvm_qemu::agent::{
+    AgentDisplayLayoutRequest, AgentFileChooserRequest, AgentFileChooserResponse, AgentPowerAck,
+    AgentPowerRequest, AgentResolutionRequest, AgentRunRequest, AgentScaleRequest,
+};
 use flatkvm_qemu::clipboard::ClipboardEvent;
-use flatkvm_qemu::dbus_notifications::{DbusNotification, DbusNotificationClosed};
+use flatkvm_qemu::dbus_notifications::{
+    DbusNotification, DbusNotificationAction, DbusNotificationClose, DbusNotificationClosed,
+    DbusNotificationStatus,
+};
+use flatkvm_qemu::devices::DeviceEvent;
 use flatkvm_qemu::runner::QemuSharedDir;
+use flatkvm_qemu::screensaver::{ScreenSaverInhibit, ScreenSaverUnInhibit};
+use flatkvm_qemu::status_notifier::{
+    StatusNotifierActivation, StatusNotifierItem, StatusNotifierItemRemoved,
+};
 
 pub enum Message {
     LocalClipboardEvent(ClipboardEvent),
     RemoteClipboardEvent(ClipboardEvent),
     DbusNotification(DbusNotification),
+    DbusNotificationClose(DbusNotificationClose),
     DbusNotificationClosed(DbusNotificationClosed),
+    DbusNotificationAction(DbusNotificationAction),
+    DbusNotificationStatus(DbusNotificationStatus),
     MountRequest(QemuSharedDir),
     RunRequest(AgentRunRequest),
     LayoutRequest(String),
+    ResolutionRequest(AgentResolutionRequest),
+    DisplayLayoutRequest(AgentDisplayLayoutRequest),
+    ScaleRequest(AgentScaleRequest),
+    OpenUri(String),
+    FileChooserRequest(AgentFileChooserRequest),
+    FileChooserResponse(AgentFileChooserResponse),
+    StatusNotifierItem(StatusNotifierItem),
+    StatusNotifierItemRemoved(StatusNotifierItemRemoved),
+    StatusNotifierActivation(StatusNotifierActivation),
+    ScreenSaverInhibit(ScreenSaverInhibit),
+    ScreenSaverUnInhibit(ScreenSaverUnInhibit),
+    DeviceEvent(DeviceEvent),
+    PowerRequest(AgentPowerRequest),
+    PowerAck(AgentPowerAck),
+    PowerOffResult(i32),
+    StatusRequest,
     AppExit(i32),
 }
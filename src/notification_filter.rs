@@ -0,0 +1,483 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// Apps running in the VM are not trusted, so everything they want to show
+// on the Host desktop is filtered here first.
+//
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const MAX_SUMMARY_LEN: usize = 128;
+pub const MAX_BODY_LEN: usize = 1024;
+pub const MAX_APP_NAME_LEN: usize = 64;
+pub const MAX_ACTIONS: usize = 6;
+pub const MAX_ACTION_KEY_LEN: usize = 64;
+pub const MAX_ACTION_LABEL_LEN: usize = 32;
+
+/// Each sender may burst up to "burst" requests, and then gets one more for
+/// every "interval" elapsed. On top of that, all senders share a global
+/// bucket, so apps can't get around their limit by opening more connections
+/// to the bus.
+#[derive(Debug)]
+pub struct Limit {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+const SENDER_LIMIT: Limit = Limit {
+    burst: 10,
+    interval: Duration::from_secs(2),
+};
+const GLOBAL_LIMIT: Limit = Limit {
+    burst: 20,
+    interval: Duration::from_millis(500),
+};
+// Stop tracking senders once we know about this many, dropping first the
+// ones that have been idle for long enough to have a full bucket, and then
+// the ones that have been idle for longer.
+const RATE_LIMIT_MAX_SENDERS: usize = 128;
+
+// The subset of the markup defined by the Desktop Notifications
+// Specification we let through. Hyperlinks and images are reduced to
+// their text.
+const ALLOWED_TAGS: [&str; 3] = ["b", "i", "u"];
+const ALLOWED_ENTITIES: [&str; 5] = ["amp", "lt", "gt", "quot", "apos"];
+
+fn truncate(text: &str, max_len: usize) -> (&str, bool) {
+    match text.char_indices().nth(max_len) {
+        Some((pos, _)) => (&text[..pos], true),
+        None => (text, false),
+    }
+}
+
+fn push_text(out: &mut String, text: &str, escape: bool) {
+    for c in text.chars() {
+        match c {
+            '\n' | '\t' => out.push(c),
+            c if c.is_control() => (),
+            '>' if escape => out.push_str("&gt;"),
+            c => out.push(c),
+        }
+    }
+}
+
+// Returns the length of the character or entity reference at the start of
+// "text", if it's a valid one.
+fn entity_len(text: &str) -> Option<usize> {
+    let end = text.char_indices().take(12).find(|&(_, c)| c == ';')?.0;
+    let name = &text[1..end];
+
+    let hex = name.strip_prefix("#x").or_else(|| name.strip_prefix("#X"));
+    let valid = if let Some(hex) = hex {
+        !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit())
+    } else if let Some(dec) = name.strip_prefix('#') {
+        !dec.is_empty() && dec.chars().all(|c| c.is_ascii_digit())
+    } else {
+        ALLOWED_ENTITIES.contains(&name)
+    };
+
+    if valid {
+        Some(end + 1)
+    } else {
+        None
+    }
+}
+
+fn handle_tag(tag: &str, out: &mut String, open: &mut Vec<&'static str>) {
+    let tag = tag.trim();
+    let (closing, tag) = match tag.strip_prefix('/') {
+        Some(tag) => (true, tag),
+        None => (false, tag),
+    };
+    let name = tag
+        .split(|c: char| c.is_whitespace() || c == '/')
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+
+    let name = match ALLOWED_TAGS.iter().find(|&&t| t == name) {
+        Some(name) => *name,
+        None => return,
+    };
+
+    if !closing {
+        open.push(name);
+        out.push_str(&format!("<{}>", name));
+    } else if open.contains(&name) {
+        // Close any other tag left open inside this one.
+        while let Some(t) = open.pop() {
+            out.push_str(&format!("</{}>", t));
+            if t == name {
+                break;
+            }
+        }
+    }
+}
+
+/// Reduces a notification body to the allowed subset of markup, dropping
+/// every other tag (keeping their text), escaping stray characters, and
+/// limiting its length to "max_len" characters of input.
+pub fn sanitize_markup(text: &str, max_len: usize) -> String {
+    let (mut rest, truncated) = truncate(text, max_len);
+    let mut out = String::with_capacity(rest.len());
+    let mut open = Vec::new();
+
+    while let Some(pos) = rest.find(['<', '&']) {
+        push_text(&mut out, &rest[..pos], true);
+        rest = &rest[pos..];
+
+        if rest.starts_with('&') {
+            match entity_len(rest) {
+                Some(len) => {
+                    out.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                }
+                None => {
+                    out.push_str("&amp;");
+                    rest = &rest[1..];
+                }
+            }
+        } else {
+            match rest.find('>') {
+                Some(end) => {
+                    handle_tag(&rest[1..end], &mut out, &mut open);
+                    rest = &rest[end + 1..];
+                }
+                // An unterminated tag, probably cut by the truncation.
+                None => rest = "",
+            }
+        }
+    }
+    push_text(&mut out, rest, true);
+
+    while let Some(tag) = open.pop() {
+        out.push_str(&format!("</{}>", tag));
+    }
+    if truncated {
+        out.push('…');
+    }
+
+    out
+}
+
+/// Removes control characters from a plain text string, and limits its
+/// length to "max_len" characters.
+pub fn sanitize_text(text: &str, max_len: usize) -> String {
+    let (text, truncated) = truncate(text, max_len);
+    let mut out = String::with_capacity(text.len());
+
+    push_text(&mut out, text, false);
+    if truncated {
+        out.push('…');
+    }
+
+    out
+}
+
+/// Filters the list of (key, label) action pairs, limiting their number and
+/// the length of their labels. Actions with oversized keys are dropped, as
+/// truncating them would make them useless to the app.
+pub fn sanitize_actions(actions: &[&str]) -> Vec<String> {
+    actions
+        .chunks(2)
+        .filter(|pair| pair.len() == 2 && pair[0].len() <= MAX_ACTION_KEY_LEN)
+        .take(MAX_ACTIONS)
+        .flat_map(|pair| {
+            vec![
+                pair[0].to_string(),
+                sanitize_text(pair[1], MAX_ACTION_LABEL_LEN),
+            ]
+        })
+        .collect()
+}
+
+fn as_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: u32,
+    last_refill: Instant,
+    last_used: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &Limit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: limit.burst,
+            last_refill: now,
+            last_used: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = as_millis(now.duration_since(self.last_refill));
+        let new_tokens = elapsed / as_millis(limit.interval);
+        if new_tokens == 0 {
+            return;
+        }
+
+        if self.tokens as u64 + new_tokens >= limit.burst as u64 {
+            self.tokens = limit.burst;
+            self.last_refill = now;
+        } else {
+            self.tokens += new_tokens as u32;
+            self.last_refill += limit.interval * new_tokens as u32;
+        }
+    }
+}
+
+/// Token bucket rate limiter, keyed by the D-Bus unique name of the sender,
+/// with a global bucket shared by all of them.
+#[derive(Debug)]
+pub struct RateLimiter {
+    sender_limit: Limit,
+    global_limit: Limit,
+    global: Bucket,
+    senders: HashMap<String, Bucket>,
+}
+
+// With the limits for notifications.
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter::new(SENDER_LIMIT, GLOBAL_LIMIT)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(sender_limit: Limit, global_limit: Limit) -> RateLimiter {
+        RateLimiter {
+            global: Bucket::new(&global_limit, Instant::now()),
+            sender_limit,
+            global_limit,
+            senders: HashMap::new(),
+        }
+    }
+
+    /// Returns true if "sender" is still allowed to go on with its request,
+    /// consuming one of its tokens.
+    pub fn allow(&mut self, sender: &str) -> bool {
+        self.allow_at(sender, Instant::now())
+    }
+
+    // Makes room for a new sender.
+    fn evict(&mut self, now: Instant) {
+        let limit = &self.sender_limit;
+        self.senders.retain(|_, bucket| {
+            bucket.refill(limit, now);
+            bucket.tokens < limit.burst
+        });
+
+        while self.senders.len() >= RATE_LIMIT_MAX_SENDERS {
+            let oldest = match self.senders.iter().min_by_key(|(_, b)| b.last_used) {
+                Some((sender, _)) => sender.clone(),
+                None => break,
+            };
+            self.senders.remove(&oldest);
+        }
+    }
+
+    fn allow_at(&mut self, sender: &str, now: Instant) -> bool {
+        if !self.senders.contains_key(sender) && self.senders.len() >= RATE_LIMIT_MAX_SENDERS {
+            self.evict(now);
+        }
+
+        let limit = &self.sender_limit;
+        let bucket = self
+            .senders
+            .entry(sender.to_string())
+            .or_insert_with(|| Bucket::new(limit, now));
+        bucket.refill(limit, now);
+        bucket.last_used = now;
+        self.global.refill(&self.global_limit, now);
+
+        if bucket.tokens == 0 || self.global.tokens == 0 {
+            return false;
+        }
+        bucket.tokens -= 1;
+        self.global.tokens -= 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_truncated() {
+        assert_eq!(sanitize_text("hello", 5), "hello");
+        assert_eq!(sanitize_text("hello world", 5), "hello…");
+        assert_eq!(sanitize_text("ñandú", 2), "ña…");
+    }
+
+    #[test]
+    fn text_control_chars_are_removed() {
+        assert_eq!(sanitize_text("a\u{7}b\u{1b}[31mc", 64), "ab[31mc");
+        assert_eq!(sanitize_text("a\nb\tc", 64), "a\nb\tc");
+        assert_eq!(sanitize_text("<b>1 > 0</b>", 64), "<b>1 > 0</b>");
+    }
+
+    #[test]
+    fn markup_allowed_tags_are_kept() {
+        assert_eq!(
+            sanitize_markup("<b>bold</b> <I>italic</I> <u>under</u>", 64),
+            "<b>bold</b> <i>italic</i> <u>under</u>"
+        );
+    }
+
+    #[test]
+    fn markup_other_tags_are_dropped() {
+        assert_eq!(
+            sanitize_markup("<a href=\"http://example.com\">link</a>", 64),
+            "link"
+        );
+        assert_eq!(sanitize_markup("x<img src=\"/tmp/a.png\"/>y", 64), "xy");
+        assert_eq!(
+            sanitize_markup("<b onclick=\"x\">bold</b>", 64),
+            "<b>bold</b>"
+        );
+    }
+
+    #[test]
+    fn markup_tags_are_balanced() {
+        assert_eq!(sanitize_markup("<b><i>x</b>y", 64), "<b><i>x</i></b>y");
+        assert_eq!(sanitize_markup("</b>x", 64), "x");
+        assert_eq!(sanitize_markup("<b>x", 64), "<b>x</b>");
+    }
+
+    #[test]
+    fn markup_entities_are_escaped() {
+        assert_eq!(
+            sanitize_markup("a &amp; b &#169; &#x41;", 64),
+            "a &amp; b &#169; &#x41;"
+        );
+        assert_eq!(
+            sanitize_markup("AT&T &foo; &#xZZ;", 64),
+            "AT&amp;T &amp;foo; &amp;#xZZ;"
+        );
+        assert_eq!(sanitize_markup("1 > 0", 64), "1 &gt; 0");
+    }
+
+    #[test]
+    fn markup_is_truncated() {
+        assert_eq!(sanitize_markup("<b>hello</b>", 6), "<b>hel</b>…");
+        assert_eq!(sanitize_markup("abc<b", 64), "abc");
+        assert_eq!(sanitize_markup("a\u{0}b", 64), "ab");
+    }
+
+    #[test]
+    fn actions_are_filtered() {
+        let long_key = "k".repeat(MAX_ACTION_KEY_LEN + 1);
+        let long_label = "l".repeat(MAX_ACTION_LABEL_LEN + 1);
+        assert_eq!(
+            sanitize_actions(&[
+                "default",
+                "Open",
+                &long_key,
+                "Bad",
+                "reply",
+                &long_label,
+                "odd"
+            ]),
+            vec![
+                "default".to_string(),
+                "Open".to_string(),
+                "reply".to_string(),
+                sanitize_text(&long_label, MAX_ACTION_LABEL_LEN),
+            ]
+        );
+    }
+
+    #[test]
+    fn actions_are_limited() {
+        let actions: Vec<&str> = ["key", "label"]
+            .iter()
+            .cycle()
+            .take((MAX_ACTIONS + 2) * 2)
+            .cloned()
+            .collect();
+        assert_eq!(sanitize_actions(&actions).len(), MAX_ACTIONS * 2);
+    }
+
+    #[test]
+    fn sender_is_limited() {
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..SENDER_LIMIT.burst {
+            assert!(limiter.allow_at(":1.1", now));
+        }
+        assert!(!limiter.allow_at(":1.1", now));
+        // Other senders have their own bucket.
+        assert!(limiter.allow_at(":1.2", now));
+
+        let later = now + SENDER_LIMIT.interval;
+        assert!(limiter.allow_at(":1.1", later));
+        assert!(!limiter.allow_at(":1.1", later));
+    }
+
+    #[test]
+    fn senders_share_global_limit() {
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        let allowed = (0..GLOBAL_LIMIT.burst * 2)
+            .filter(|i| limiter.allow_at(&format!(":1.{}", i), now))
+            .count();
+        assert_eq!(allowed, GLOBAL_LIMIT.burst as usize);
+        assert!(limiter.allow_at(":1.0", now + GLOBAL_LIMIT.interval));
+    }
+
+    #[test]
+    fn custom_limits() {
+        let mut limiter = RateLimiter::new(
+            Limit {
+                burst: 1,
+                interval: Duration::from_secs(10),
+            },
+            Limit {
+                burst: 2,
+                interval: Duration::from_secs(10),
+            },
+        );
+        let now = Instant::now();
+
+        assert!(limiter.allow_at(":1.1", now));
+        assert!(!limiter.allow_at(":1.1", now));
+        assert!(limiter.allow_at(":1.2", now));
+        assert!(!limiter.allow_at(":1.3", now));
+        assert!(limiter.allow_at(":1.1", now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn senders_are_capped() {
+        let mut limiter = RateLimiter::default();
+        let mut now = Instant::now();
+
+        for i in 0..RATE_LIMIT_MAX_SENDERS * 4 {
+            // Keep the first sender busy, so it's always the newest.
+            limiter.allow_at(":1.0", now);
+            limiter.allow_at(&format!(":2.{}", i), now);
+            assert!(limiter.senders.len() <= RATE_LIMIT_MAX_SENDERS);
+            now += Duration::from_millis(1);
+        }
+        assert!(limiter.senders.contains_key(":1.0"));
+    }
+}
@@ -0,0 +1,277 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// Minimal implementation of the desktop portal interfaces, relaying the
+// requests from the apps in the VM to the Host.
+//
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dbus::arg::{RefArg, Variant};
+use dbus::tree;
+use dbus::{Connection, Path};
+use log::debug;
+
+use flatkvm_qemu::agent::AgentFileChooserRequest;
+
+use crate::dbus_listener::{self, HostSender};
+use crate::event_channel::EventSender;
+use crate::message::Message;
+use crate::notification_filter::{sanitize_text, Limit, RateLimiter};
+
+const PORTAL_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const OPENURI_IFACE: &str = "org.freedesktop.portal.OpenURI";
+const FILECHOOSER_IFACE: &str = "org.freedesktop.portal.FileChooser";
+const REQUEST_IFACE: &str = "org.freedesktop.portal.Request";
+const OPENURI_VERSION: u32 = 2;
+const FILECHOOSER_VERSION: u32 = 1;
+const MAX_TITLE_LEN: usize = 256;
+
+// Only these are relayed to the Host, which applies its own policy on top.
+const ALLOWED_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+const MAX_URI_LEN: usize = 2048;
+
+// Each of these opens a browser or a dialog on the Host, so apps get far
+// fewer of them than notifications.
+const SENDER_LIMIT: Limit = Limit {
+    burst: 3,
+    interval: Duration::from_secs(5),
+};
+const GLOBAL_LIMIT: Limit = Limit {
+    burst: 5,
+    interval: Duration::from_secs(2),
+};
+
+// Response codes for the Request::Response signal.
+const RESPONSE_SUCCESS: u32 = 0;
+const RESPONSE_CANCELLED: u32 = 1;
+const RESPONSE_OTHER: u32 = 2;
+
+type Options<'a> = HashMap<&'a str, Variant<Box<dyn RefArg>>>;
+
+fn validate_uri(uri: &str) -> Result<(), String> {
+    if uri.len() > MAX_URI_LEN {
+        return Err("URI too long".to_string());
+    }
+    if uri.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return Err("URI contains invalid characters".to_string());
+    }
+
+    let scheme = uri.split(':').next().unwrap_or("").to_lowercase();
+    if !ALLOWED_SCHEMES.contains(&scheme.as_str()) {
+        return Err(format!("scheme \"{}\" not allowed", scheme));
+    }
+
+    Ok(())
+}
+
+fn response_signal(handle: &Path<'static>, response: u32, uris: Vec<String>) -> dbus::Message {
+    let mut results: HashMap<&str, Variant<Vec<String>>> = HashMap::new();
+    if !uris.is_empty() {
+        results.insert("uris", Variant(uris));
+    }
+
+    dbus::Message::signal(handle, &REQUEST_IFACE.into(), &"Response".into())
+        .append2(response, results)
+}
+
+/// Events coming from the Host that complete a pending portal request.
+pub enum PortalEvent {
+    /// The Host has shared the file picked by the user, and it has been
+    /// made available at the given URI (None if the user cancelled).
+    FileChosen(u32, Option<String>),
+    /// The file picked by the user couldn't be made available.
+    FileChooserFailed(u32),
+}
+
+pub struct Portal {
+    sender: HostSender,
+    // FileChooser requests waiting for an answer from the Host, with the
+    // handle of the Request object to be signaled.
+    pending: Mutex<HashMap<u32, Path<'static>>>,
+    limiter: Mutex<RateLimiter>,
+    // Token for the next request without a handle_token.
+    next_token: AtomicUsize,
+    next_filechooser_id: AtomicUsize,
+}
+
+impl Portal {
+    pub fn new(sender: EventSender<Message>) -> Portal {
+        Portal {
+            sender: HostSender::new(sender),
+            pending: Mutex::new(HashMap::new()),
+            limiter: Mutex::new(RateLimiter::new(SENDER_LIMIT, GLOBAL_LIMIT)),
+            next_token: AtomicUsize::new(0),
+            next_filechooser_id: AtomicUsize::new(1),
+        }
+    }
+
+    // Builds the object path for the Request, as described in the
+    // org.freedesktop.portal.Request documentation.
+    fn request_path(&self, sender: &str, options: &Options) -> Result<Path<'static>, String> {
+        let sender = sender.trim_start_matches(':').replace('.', "_");
+        let token = match options.get("handle_token").and_then(|t| t.0.as_str()) {
+            Some(token) => token.to_string(),
+            None => format!("flatkvm{}", self.next_token.fetch_add(1, Ordering::SeqCst)),
+        };
+
+        Path::new(format!("{}/request/{}/{}", PORTAL_PATH, sender, token))
+    }
+
+    fn check_rate(&self, caller: &str) -> Result<(), String> {
+        if !self.limiter.lock().unwrap().allow(caller) {
+            debug!(
+                "dropping portal request from {}: rate limit exceeded",
+                caller
+            );
+            return Err("rate limit exceeded".to_string());
+        }
+        Ok(())
+    }
+
+    fn new_filechooser_id(&self) -> u32 {
+        self.next_filechooser_id.fetch_add(1, Ordering::SeqCst) as u32
+    }
+
+    /// Whether a FileChooser request is still waiting for an answer from
+    /// the Host.
+    pub fn is_pending(&self, id: u32) -> bool {
+        self.pending.lock().unwrap().contains_key(&id)
+    }
+
+    /// Returns the Response signal completing the request the event refers
+    /// to, if it's still pending.
+    pub fn event_message(&self, event: PortalEvent) -> Option<dbus::Message> {
+        match event {
+            PortalEvent::FileChosen(id, uri) => {
+                let handle = self.pending.lock().unwrap().remove(&id)?;
+                Some(match uri {
+                    Some(uri) => response_signal(&handle, RESPONSE_SUCCESS, vec![uri]),
+                    None => response_signal(&handle, RESPONSE_CANCELLED, Vec::new()),
+                })
+            }
+            PortalEvent::FileChooserFailed(id) => {
+                let handle = self.pending.lock().unwrap().remove(&id)?;
+                Some(response_signal(&handle, RESPONSE_OTHER, Vec::new()))
+            }
+        }
+    }
+}
+
+fn create_tree(portal: Arc<Portal>) -> tree::Tree<tree::MTFn<()>, ()> {
+    let f = tree::Factory::new_fn::<()>();
+
+    let p = portal.clone();
+    let open_uri = f
+        .method("OpenURI", (), move |m| {
+            let (_parent_window, uri, options): (&str, &str, Options) = m.msg.read3()?;
+            let caller = m.msg.sender().map_or(String::new(), |s| s.to_string());
+            p.check_rate(&caller)
+                .map_err(|err| tree::MethodErr::failed(&err))?;
+            let handle = p
+                .request_path(&caller, &options)
+                .map_err(|err| tree::MethodErr::failed(&err))?;
+
+            let response = match validate_uri(uri) {
+                Ok(_) => {
+                    debug!("relaying URI to the Host: {}", uri);
+                    p.sender.send(Message::OpenUri(uri.to_string()));
+                    RESPONSE_SUCCESS
+                }
+                Err(err) => {
+                    debug!("refusing to open URI: {}", err);
+                    RESPONSE_OTHER
+                }
+            };
+
+            let signal = response_signal(&handle, response, Vec::new());
+            Ok(vec![m.msg.method_return().append1(handle), signal])
+        })
+        .inarg::<&str, _>("parent_window")
+        .inarg::<&str, _>("uri")
+        .inarg::<Options, _>("options")
+        .outarg::<Path, _>("handle");
+
+    let version = f.property::<u32, _>("version", ()).on_get(|i, _| {
+        i.append(OPENURI_VERSION);
+        Ok(())
+    });
+
+    let openuri_iface = f
+        .interface(OPENURI_IFACE, ())
+        .add_m(open_uri)
+        .add_p(version);
+
+    // The file is picked on the Host, which shares it with us in a new
+    // directory. The Response is signaled once it has been mounted.
+    let p = portal.clone();
+    let open_file = f
+        .method("OpenFile", (), move |m| {
+            let (_parent_window, title, options): (&str, &str, Options) = m.msg.read3()?;
+            let caller = m.msg.sender().map_or(String::new(), |s| s.to_string());
+            p.check_rate(&caller)
+                .map_err(|err| tree::MethodErr::failed(&err))?;
+            let handle = p
+                .request_path(&caller, &options)
+                .map_err(|err| tree::MethodErr::failed(&err))?;
+
+            let id = p.new_filechooser_id();
+            p.pending.lock().unwrap().insert(id, handle.clone());
+            debug!("asking the Host to choose a file, request {}", id);
+            p.sender
+                .send(Message::FileChooserRequest(AgentFileChooserRequest {
+                    id,
+                    title: sanitize_text(title, MAX_TITLE_LEN),
+                }));
+
+            Ok(vec![m.msg.method_return().append1(handle)])
+        })
+        .inarg::<&str, _>("parent_window")
+        .inarg::<&str, _>("title")
+        .inarg::<Options, _>("options")
+        .outarg::<Path, _>("handle");
+
+    let version = f.property::<u32, _>("version", ()).on_get(|i, _| {
+        i.append(FILECHOOSER_VERSION);
+        Ok(())
+    });
+
+    let filechooser_iface = f
+        .interface(FILECHOOSER_IFACE, ())
+        .add_m(open_file)
+        .add_p(version);
+
+    f.tree(()).add(
+        f.object_path(PORTAL_PATH, ())
+            .introspectable()
+            .add(openuri_iface)
+            .add(filechooser_iface),
+    )
+}
+
+/// Registers the portal service on the bus. The VM template doesn't ship
+/// xdg-desktop-portal, so we try to take over the name if someone else owns
+/// it.
+pub fn register(c: &Connection, portal: Arc<Portal>) -> Result<(), String> {
+    dbus_listener::serve(c, PORTAL_NAME, create_tree(portal))?;
+
+    Ok(())
+}
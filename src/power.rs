@@ -0,0 +1,145 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// Graceful shutdown, reboot and suspend of the guest.
+//
+
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use flatkvm_qemu::agent::{AgentPowerAck, PowerAction, PowerPhase};
+use log::{debug, error, info};
+
+use crate::apps::Apps;
+use crate::event_channel::EventSender;
+use crate::message::Message;
+
+// How long the apps have to quit before we carry on anyway.
+const APPS_QUIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn run_sudo(args: &[&str]) -> i32 {
+    debug!("running sudo with args: {:?}", args);
+    match Command::new("sudo").args(args).status() {
+        Ok(exit_status) => exit_status.code().unwrap_or(-1),
+        Err(err) => {
+            error!("can't run {}: {}", args[0], err.to_string());
+            -1
+        }
+    }
+}
+
+// Unmounts the directories shared by the Host, in the reverse order they
+// were mounted so nested mounts go first. Returns 0 if all of them were
+// unmounted.
+fn unmount_all(mounts: &[String]) -> i32 {
+    let mut result = 0;
+    for target in mounts.iter().rev() {
+        let code = run_sudo(&["umount", target.as_str()]);
+        if code != 0 {
+            error!("can't unmount {}: exit code {}", target, code);
+            result = code;
+        }
+    }
+    result
+}
+
+fn sync() {
+    info!("flushing filesystems");
+    // Safe because sync() doesn't touch our memory.
+    unsafe { libc::sync() };
+}
+
+fn power_action(action: &PowerAction) -> i32 {
+    let verb = match action {
+        PowerAction::Shutdown => "poweroff",
+        PowerAction::Reboot => "reboot",
+        PowerAction::Suspend => "suspend",
+    };
+    run_sudo(&["systemctl", verb])
+}
+
+/// Returns the first phase of the power action, which is the one a
+/// rejected request is acked with.
+pub fn first_phase(action: &PowerAction) -> PowerPhase {
+    match action {
+        PowerAction::Suspend => PowerPhase::Synced,
+        _ => PowerPhase::AppsStopped,
+    }
+}
+
+/// Carries out the power action in a separate thread, sending the result
+/// of each phase so it can be acked to the Host. Apps are left alone and
+/// the shared directories stay mounted on suspend, as they'll be used
+/// again on resume.
+pub fn handle_request(
+    action: PowerAction,
+    apps: Apps,
+    mounts: Vec<String>,
+    sender: EventSender<Message>,
+) {
+    let ack_action = action.clone();
+    let ack = move |phase, result| {
+        sender
+            .send(Message::PowerAck(AgentPowerAck {
+                action: ack_action.clone(),
+                phase,
+                result,
+            }))
+            .unwrap();
+    };
+
+    thread::spawn(move || {
+        let suspend = action == PowerAction::Suspend;
+
+        if !suspend {
+            info!("asking apps to quit");
+            apps.terminate();
+            let result = if apps.wait_all(APPS_QUIT_TIMEOUT) {
+                0
+            } else {
+                error!("apps didn't quit in time, carrying on");
+                -1
+            };
+            ack(PowerPhase::AppsStopped, result);
+        }
+
+        sync();
+        ack(PowerPhase::Synced, 0);
+
+        if !suspend {
+            info!("unmounting shared directories");
+            ack(PowerPhase::Unmounted, unmount_all(&mounts));
+        }
+
+        info!("starting power action: {:?}", action);
+        ack(PowerPhase::PowerAction, power_action(&action));
+    });
+}
+
+/// Flushes the filesystems, unmounts the shared directories and powers off
+/// on our own, once all apps have exited. The result of the power action is
+/// sent back, as there's no request from the Host to ack.
+pub fn power_off(mounts: Vec<String>, sender: EventSender<Message>) {
+    thread::spawn(move || {
+        sync();
+        unmount_all(&mounts);
+        info!("no apps left, powering off");
+        let result = power_action(&PowerAction::Shutdown);
+        sender.send(Message::PowerOffResult(result)).unwrap();
+    });
+}
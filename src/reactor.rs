@@ -0,0 +1,120 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// Single threaded reactor multiplexing the event sources of the agent. The
+// sets of fds are small, and some of them (the D-Bus watches) may change at
+// any time, so they're collected again for each call to poll() instead of
+// keeping them registered with epoll.
+//
+
+use std::io;
+use std::time::Instant;
+
+/// Something the reactor waits on.
+pub trait Source {
+    /// Returns the fds to be polled, with the events of interest.
+    fn fds(&self) -> Vec<libc::pollfd>;
+
+    /// Processes the events reported for one of the fds of the source.
+    fn handle(&mut self, pfd: &libc::pollfd) -> Result<(), String>;
+
+    /// Returns when the source needs to be woken up even if none of its fds
+    /// is ready (i.e. to retry a connection).
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Called once the deadline has passed.
+    fn handle_deadline(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Converts the time left until the deadline to a poll() timeout, rounding
+// up so we don't wake up before it has actually passed.
+fn poll_timeout(deadline: Instant) -> libc::c_int {
+    let now = Instant::now();
+    if deadline <= now {
+        return 0;
+    }
+    let left = deadline - now;
+    let ms = left.as_secs() * 1000 + (u64::from(left.subsec_nanos()) + 999_999) / 1_000_000;
+    if ms > libc::c_int::max_value() as u64 {
+        libc::c_int::max_value()
+    } else {
+        ms as libc::c_int
+    }
+}
+
+pub struct Reactor<'a> {
+    sources: Vec<Box<dyn Source + 'a>>,
+}
+
+impl<'a> Reactor<'a> {
+    pub fn new() -> Reactor<'a> {
+        Reactor {
+            sources: Vec::new(),
+        }
+    }
+
+    pub fn add_source(&mut self, source: Box<dyn Source + 'a>) {
+        self.sources.push(source);
+    }
+
+    /// Dispatches the events of all sources until one of them fails.
+    pub fn run(&mut self) -> Result<(), String> {
+        loop {
+            let mut fds = Vec::new();
+            let mut owners = Vec::new();
+            for (i, source) in self.sources.iter().enumerate() {
+                for pfd in source.fds() {
+                    fds.push(pfd);
+                    owners.push(i);
+                }
+            }
+
+            let timeout = match self.sources.iter().filter_map(|s| s.deadline()).min() {
+                Some(deadline) => poll_timeout(deadline),
+                None => -1,
+            };
+
+            // Safe because fds is a valid array of pollfd structs.
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.to_string());
+            }
+
+            for (pfd, &i) in fds.iter().zip(owners.iter()) {
+                if pfd.revents != 0 {
+                    self.sources[i].handle(pfd)?;
+                }
+            }
+
+            let now = Instant::now();
+            for source in self.sources.iter_mut() {
+                match source.deadline() {
+                    Some(deadline) if deadline <= now => source.handle_deadline()?,
+                    _ => (),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// org.freedesktop.ScreenSaver inhibition relay, so apps like video players
+// can keep the Host screen from locking.
+//
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use dbus::tree;
+use dbus::Connection;
+use log::debug;
+
+use flatkvm_qemu::screensaver::{ScreenSaverInhibit, ScreenSaverUnInhibit};
+
+use crate::dbus_listener::{self, HostSender};
+use crate::event_channel::EventSender;
+use crate::message::Message;
+use crate::notification_filter::sanitize_text;
+
+const SCREENSAVER_NAME: &str = "org.freedesktop.ScreenSaver";
+// Apps use either of these paths, so we serve both.
+const SCREENSAVER_PATHS: [&str; 2] = ["/org/freedesktop/ScreenSaver", "/ScreenSaver"];
+// No single app needs more than a few of these, so don't let any of them
+// hold more than this many inhibitions, nor use up those of the others.
+const MAX_INHIBITORS_PER_OWNER: usize = 8;
+// Keeps the number of inhibitions on the Host bounded, even with apps
+// opening several connections to the bus.
+const MAX_INHIBITORS: usize = 128;
+const MAX_APP_NAME_LEN: usize = 64;
+const MAX_REASON_LEN: usize = 128;
+
+#[derive(Default)]
+struct Inhibitors {
+    last_cookie: u32,
+    // Unique bus name of the owner of each inhibition.
+    owners: HashMap<u32, String>,
+}
+
+pub struct ScreenSaver {
+    sender: HostSender,
+    inhibitors: Mutex<Inhibitors>,
+}
+
+impl ScreenSaver {
+    pub fn new(sender: EventSender<Message>) -> ScreenSaver {
+        ScreenSaver {
+            sender: HostSender::new(sender),
+            inhibitors: Mutex::new(Inhibitors::default()),
+        }
+    }
+
+    fn inhibit(&self, owner: &str, app_name: &str, reason: &str) -> Result<u32, String> {
+        let mut inhibitors = self.inhibitors.lock().unwrap();
+        if inhibitors.owners.values().filter(|o| *o == owner).count() >= MAX_INHIBITORS_PER_OWNER
+            || inhibitors.owners.len() >= MAX_INHIBITORS
+        {
+            return Err("too many inhibitions".to_string());
+        }
+
+        // Cookies are never 0, and never reused while still active.
+        let cookie = loop {
+            inhibitors.last_cookie = inhibitors.last_cookie.wrapping_add(1);
+            let cookie = inhibitors.last_cookie;
+            if cookie != 0 && !inhibitors.owners.contains_key(&cookie) {
+                break cookie;
+            }
+        };
+        inhibitors.owners.insert(cookie, owner.to_string());
+
+        self.sender
+            .send(Message::ScreenSaverInhibit(ScreenSaverInhibit {
+                cookie,
+                app_name: sanitize_text(app_name, MAX_APP_NAME_LEN),
+                reason: sanitize_text(reason, MAX_REASON_LEN),
+            }));
+        Ok(cookie)
+    }
+
+    fn uninhibit(&self, owner: &str, cookie: u32) -> Result<(), String> {
+        let mut inhibitors = self.inhibitors.lock().unwrap();
+        match inhibitors.owners.get(&cookie) {
+            Some(o) if o == owner => (),
+            _ => return Err(format!("unknown cookie {}", cookie)),
+        }
+        inhibitors.owners.remove(&cookie);
+
+        self.sender
+            .send(Message::ScreenSaverUnInhibit(ScreenSaverUnInhibit {
+                cookie,
+            }));
+        Ok(())
+    }
+
+    // Releases the inhibitions whose owner matches the predicate.
+    fn release<F: Fn(&str) -> bool>(&self, matches: F) {
+        let mut inhibitors = self.inhibitors.lock().unwrap();
+        let cookies: Vec<u32> = inhibitors
+            .owners
+            .iter()
+            .filter(|(_, owner)| matches(owner))
+            .map(|(&cookie, _)| cookie)
+            .collect();
+        for cookie in cookies {
+            if let Some(owner) = inhibitors.owners.remove(&cookie) {
+                debug!("releasing inhibition {} from {}", cookie, owner);
+            }
+            self.sender
+                .send(Message::ScreenSaverUnInhibit(ScreenSaverUnInhibit {
+                    cookie,
+                }));
+        }
+    }
+
+    /// Releases the inhibitions held by apps that have left the bus.
+    pub fn handle_signal(&self, msg: &dbus::Message) {
+        if let Some(name) = dbus_listener::name_lost(msg) {
+            self.release(|owner| owner == name);
+        }
+    }
+
+    /// Releases all inhibitions. Called when we lose the connection to the
+    /// bus, as we can no longer tell when their owners go away.
+    pub fn release_all(&self) {
+        self.release(|_| true);
+    }
+}
+
+fn create_tree(screensaver: Arc<ScreenSaver>) -> tree::Tree<tree::MTFn<()>, ()> {
+    let f = tree::Factory::new_fn::<()>();
+    let mut tree = f.tree(());
+
+    for path in SCREENSAVER_PATHS.iter() {
+        let s = screensaver.clone();
+        let inhibit = f
+            .method("Inhibit", (), move |m| {
+                let (app_name, reason): (&str, &str) = m.msg.read2()?;
+                let owner = m.msg.sender().map_or(String::new(), |s| s.to_string());
+                let cookie = s
+                    .inhibit(&owner, app_name, reason)
+                    .map_err(|err| tree::MethodErr::failed(&err))?;
+                debug!("screensaver inhibited by {}: {}", owner, cookie);
+                Ok(vec![m.msg.method_return().append1(cookie)])
+            })
+            .inarg::<&str, _>("application_name")
+            .inarg::<&str, _>("reason_for_inhibit")
+            .outarg::<u32, _>("cookie");
+
+        let s = screensaver.clone();
+        let uninhibit = f
+            .method("UnInhibit", (), move |m| {
+                let cookie: u32 = m.msg.read1()?;
+                let owner = m.msg.sender().map_or(String::new(), |s| s.to_string());
+                s.uninhibit(&owner, cookie)
+                    .map_err(|err| tree::MethodErr::failed(&err))?;
+                Ok(vec![m.msg.method_return()])
+            })
+            .inarg::<u32, _>("cookie");
+
+        let iface = f
+            .interface(SCREENSAVER_NAME, ())
+            .add_m(inhibit)
+            .add_m(uninhibit);
+        tree = tree.add(f.object_path(*path, ()).introspectable().add(iface));
+    }
+
+    tree
+}
+
+/// Registers the ScreenSaver service on the bus, and subscribes to the
+/// signals needed to release the inhibitions of apps that exit.
+pub fn register(c: &Connection, screensaver: Arc<ScreenSaver>) -> Result<(), String> {
+    c.add_match(dbus_listener::NAME_OWNER_CHANGED_RULE)
+        .map_err(|err| err.to_string())?;
+    dbus_listener::serve(c, SCREENSAVER_NAME, create_tree(screensaver))?;
+
+    Ok(())
+}
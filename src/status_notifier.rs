@@ -0,0 +1,646 @@
+// flatkvm-agent
+// Copyright (C) 2019  Sergio Lopez <slp@sinrega.org>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//
+// StatusNotifierWatcher implementation, relaying the tray icons registered
+// by the apps in the VM to the Host, and the user interactions with them
+// back to the apps.
+//
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dbus::arg::{RefArg, Variant};
+use dbus::tree;
+use dbus::{Connection, Path};
+use log::{debug, error};
+
+use flatkvm_qemu::status_notifier::{
+    StatusNotifierActivation, StatusNotifierItem, StatusNotifierItemRemoved, StatusNotifierMenuItem,
+};
+
+use crate::dbus_listener::{self, HostSender};
+use crate::event_channel::EventSender;
+use crate::icon;
+use crate::message::Message;
+use crate::notification_filter::sanitize_text;
+
+const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+const ITEM_IFACE: &str = "org.kde.StatusNotifierItem";
+const ITEM_DEFAULT_PATH: &str = "/StatusNotifierItem";
+const MENU_IFACE: &str = "com.canonical.dbusmenu";
+const PROPERTIES_IFACE: &str = "org.freedesktop.DBus.Properties";
+
+// The calls to the items to fetch their properties are sent without waiting
+// for the reply. Those not answered in time are given up, and those that
+// change too often are fetched at most once per interval.
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_FETCH_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_ITEMS: usize = 16;
+const MAX_MENU_ITEMS: usize = 64;
+const MAX_MENU_DEPTH: usize = 4;
+const MAX_LABEL_LEN: usize = 64;
+// Applies to the title, status and tooltip of the items.
+const MAX_TEXT_LEN: usize = 128;
+
+type Properties = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+#[derive(Clone)]
+struct Item {
+    bus_name: String,
+    // Unique name of the connection owning bus_name, which is the one the
+    // signals of the item come from. Unknown until GetNameOwner replies.
+    owner: Option<String>,
+    path: String,
+    menu_path: Option<String>,
+    // Properties have changed since the last time we relayed them.
+    dirty: bool,
+    // When we last asked the item for its properties.
+    last_fetch: Option<Instant>,
+    // When the call to the item still waiting for a reply was sent.
+    call_sent: Option<Instant>,
+}
+
+impl Item {
+    // Returns when the item needs our attention, either to fetch its
+    // properties or to give up on a call it hasn't answered.
+    fn deadline(&self) -> Option<Instant> {
+        match (self.call_sent, self.last_fetch) {
+            (Some(sent), _) => Some(sent + CALL_TIMEOUT),
+            (None, _) if !self.dirty => None,
+            (None, Some(last)) => Some(last + MIN_FETCH_INTERVAL),
+            (None, None) => Some(Instant::now()),
+        }
+    }
+}
+
+// Calls sent to the items, waiting for a reply.
+enum Call {
+    // GetNameOwner on the bus, to learn the owner of the item's bus name.
+    Owner,
+    // Properties.GetAll on the item.
+    Properties,
+    // dbusmenu's GetLayout, with the state of the item fetched so far.
+    Menu(StatusNotifierItem),
+}
+
+pub struct StatusNotifierWatcher {
+    sender: HostSender,
+    // Registered items, indexed by their "bus_name/path" identifier.
+    items: Mutex<HashMap<String, Item>>,
+    // Id of the item each call was sent to, indexed by the call serial.
+    calls: Mutex<HashMap<u32, (String, Call)>>,
+}
+
+fn prop_str(props: &Properties, name: &str) -> String {
+    props
+        .get(name)
+        .and_then(|v| v.0.as_str())
+        .map_or(String::new(), |s| sanitize_text(s, MAX_TEXT_LEN))
+}
+
+// The ToolTip property has the (sa(iiay)ss) signature, being the third
+// field the title of the tooltip.
+fn prop_tooltip(props: &Properties) -> String {
+    props
+        .get("ToolTip")
+        .and_then(|v| v.0.as_iter())
+        .and_then(|mut fields| fields.nth(2))
+        .and_then(|title| title.as_str())
+        .map_or(String::new(), |s| sanitize_text(s, MAX_TEXT_LEN))
+}
+
+// Parses a (ia{sv}av) layout node, as returned by dbusmenu's GetLayout.
+fn parse_menu_item(
+    node: &dyn RefArg,
+    depth: usize,
+    count: &mut usize,
+) -> Option<StatusNotifierMenuItem> {
+    *count += 1;
+    if *count > MAX_MENU_ITEMS {
+        return None;
+    }
+
+    let mut fields = node.as_iter()?;
+    let id = fields.next()?.as_i64()? as i32;
+
+    let mut label = String::new();
+    let mut enabled = true;
+    let mut visible = true;
+    let mut separator = false;
+    // Dictionaries are iterated as a sequence of keys and values.
+    let mut props = fields.next()?.as_iter()?;
+    while let (Some(key), Some(value)) = (props.next(), props.next()) {
+        match key.as_str() {
+            Some("label") => {
+                let l = value.as_str().unwrap_or("").replace('_', "");
+                label = sanitize_text(&l, MAX_LABEL_LEN);
+            }
+            Some("enabled") => enabled = value.as_i64().map_or(true, |e| e != 0),
+            Some("visible") => visible = value.as_i64().map_or(true, |v| v != 0),
+            Some("type") => separator = value.as_str() == Some("separator"),
+            _ => (),
+        }
+    }
+    if !visible {
+        return None;
+    }
+
+    let mut children = Vec::new();
+    if depth < MAX_MENU_DEPTH {
+        if let Some(nodes) = fields.next().and_then(|c| c.as_iter()) {
+            // Each child comes wrapped in a variant.
+            for child in nodes {
+                if let Some(item) = child
+                    .as_iter()
+                    .and_then(|mut inner| inner.next())
+                    .and_then(|inner| parse_menu_item(inner, depth + 1, count))
+                {
+                    children.push(item);
+                }
+            }
+        }
+    }
+
+    Some(StatusNotifierMenuItem {
+        id,
+        label,
+        enabled,
+        separator,
+        children,
+    })
+}
+
+// Parses the reply to GetLayout, returning the top level entries.
+fn parse_menu(reply: &dbus::Message) -> Result<Vec<StatusNotifierMenuItem>, String> {
+    let mut args = reply.iter_init();
+    args.next();
+    let layout = args.get_refarg().ok_or("invalid menu layout".to_string())?;
+
+    // The root node is just a container for the top level entries.
+    let mut count = 0;
+    Ok(parse_menu_item(&*layout, 0, &mut count).map_or(Vec::new(), |root| root.children))
+}
+
+// Parses the reply to GetAll, returning the state of the item without its
+// menu, and the path of the latter.
+fn parse_item(
+    id: &str,
+    reply: &dbus::Message,
+) -> Result<(StatusNotifierItem, Option<String>), String> {
+    let props: Properties = reply.read1().map_err(|err| err.to_string())?;
+
+    let icon_name = props
+        .get("IconName")
+        .and_then(|v| v.0.as_str())
+        .unwrap_or("");
+    let icon = icon::status_notifier_icon(icon_name, props.get("IconPixmap").map(|v| &*v.0));
+
+    let menu_path = props
+        .get("Menu")
+        .and_then(|v| v.0.as_str())
+        .map(|p| p.to_string());
+
+    Ok((
+        StatusNotifierItem {
+            id: id.to_string(),
+            title: prop_str(&props, "Title"),
+            status: prop_str(&props, "Status"),
+            tooltip: prop_tooltip(&props),
+            icon,
+            menu: Vec::new(),
+        },
+        menu_path,
+    ))
+}
+
+impl StatusNotifierWatcher {
+    pub fn new(sender: EventSender<Message>) -> StatusNotifierWatcher {
+        StatusNotifierWatcher {
+            sender: HostSender::new(sender),
+            items: Mutex::new(HashMap::new()),
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn registered_items(&self) -> Vec<String> {
+        self.items.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn register_item(
+        &self,
+        bus_name: &str,
+        owner: Option<&str>,
+        path: &str,
+    ) -> Result<String, String> {
+        let id = format!("{}{}", bus_name, path);
+        let mut items = self.items.lock().unwrap();
+
+        if !items.contains_key(&id) && items.len() >= MAX_ITEMS {
+            return Err("too many items registered".to_string());
+        }
+        items.insert(
+            id.clone(),
+            Item {
+                bus_name: bus_name.to_string(),
+                owner: owner.map(|o| o.to_string()),
+                path: path.to_string(),
+                menu_path: None,
+                dirty: true,
+                last_fetch: None,
+                call_sent: None,
+            },
+        );
+
+        Ok(id)
+    }
+
+    // Removes the items registered by the name or its owner.
+    fn remove_items(&self, name: &str) -> Vec<String> {
+        let mut items = self.items.lock().unwrap();
+        let removed: Vec<String> = items
+            .iter()
+            .filter(|(_, item)| {
+                item.bus_name == name || item.owner.as_ref().map_or(false, |o| o == name)
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in removed.iter() {
+            items.remove(id);
+            self.forget_calls(id);
+            self.sender.send(Message::StatusNotifierItemRemoved(
+                StatusNotifierItemRemoved { id: id.clone() },
+            ));
+        }
+
+        removed
+    }
+
+    /// Removes all items, along with the calls still waiting for a reply.
+    /// Called when we lose the connection to the bus, as the items need to
+    /// register again on the new one.
+    pub fn remove_all(&self) {
+        let ids: Vec<String> = self
+            .items
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(id, _)| id)
+            .collect();
+        self.calls.lock().unwrap().clear();
+        for id in ids {
+            self.sender.send(Message::StatusNotifierItemRemoved(
+                StatusNotifierItemRemoved { id },
+            ));
+        }
+    }
+
+    fn forget_calls(&self, id: &str) {
+        self.calls.lock().unwrap().retain(|_, (i, _)| i != id);
+    }
+
+    // Sends a call to the item, to be processed by handle_reply once the
+    // reply arrives.
+    fn send_call(
+        &self,
+        c: &Connection,
+        id: &str,
+        item: &mut Item,
+        msg: dbus::Message,
+        call: Call,
+    ) -> Result<(), String> {
+        let serial = c
+            .send(msg)
+            .map_err(|_| "sending DBus call failed".to_string())?;
+        item.call_sent = Some(Instant::now());
+        self.calls
+            .lock()
+            .unwrap()
+            .insert(serial, (id.to_string(), call));
+        Ok(())
+    }
+
+    fn unregistered_signals(&self, ids: Vec<String>) -> Vec<dbus::Message> {
+        let path: Path<'static> = WATCHER_PATH.into();
+        ids.into_iter()
+            .map(|id| {
+                dbus::Message::signal(
+                    &path,
+                    &WATCHER_NAME.into(),
+                    &"StatusNotifierItemUnregistered".into(),
+                )
+                .append1(id)
+            })
+            .collect()
+    }
+
+    /// Processes a signal received on the bus, returning the signals we
+    /// need to emit in response.
+    pub fn handle_signal(&self, msg: &dbus::Message) -> Vec<dbus::Message> {
+        if let Some(name) = dbus_listener::name_lost(msg) {
+            return self.unregistered_signals(self.remove_items(&name));
+        }
+
+        // Items signal any change in their properties, and their menus any
+        // change in their layout or entries, so we need to fetch them again.
+        let (_, path, iface, member) = msg.headers();
+        let iface = iface.as_ref().map(|i| i.as_str());
+        let member = member.as_ref().map(|m| m.as_str());
+        let item_changed = iface == Some(ITEM_IFACE);
+        let menu_changed = iface == Some(MENU_IFACE)
+            && (member == Some("LayoutUpdated") || member == Some("ItemsPropertiesUpdated"));
+        if !item_changed && !menu_changed {
+            return Vec::new();
+        }
+
+        let sender = msg.sender().map_or(String::new(), |s| s.to_string());
+        let mut items = self.items.lock().unwrap();
+        for item in items
+            .values_mut()
+            .filter(|i| i.owner.as_ref() == Some(&sender))
+        {
+            if item_changed || (path.is_some() && item.menu_path == path) {
+                item.dirty = true;
+            }
+        }
+
+        Vec::new()
+    }
+
+    /// Processes the reply to one of the calls we've sent to the items,
+    /// relaying their state to the Host once we have all of it.
+    pub fn handle_reply(&self, c: &Connection, reply: &dbus::Message) {
+        let serial = match reply.get_reply_serial() {
+            Some(serial) => serial,
+            None => return,
+        };
+        let (id, call) = match self.calls.lock().unwrap().remove(&serial) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let mut items = self.items.lock().unwrap();
+        let item = match items.get_mut(&id) {
+            Some(item) => item,
+            None => return,
+        };
+        item.call_sent = None;
+
+        if reply.msg_type() == dbus::MessageType::Error {
+            debug!("call to {} failed: {:?}", id, reply.get1::<&str>());
+            // Nobody owns the name the item was registered with.
+            if let Call::Owner = call {
+                let bus_name = item.bus_name.clone();
+                drop(items);
+                for signal in self.unregistered_signals(self.remove_items(&bus_name)) {
+                    if c.send(signal).is_err() {
+                        error!("sending DBus signal failed");
+                    }
+                }
+                return;
+            }
+            // Relay the item anyway if it's just the menu that's missing.
+            if let Call::Menu(sni) = call {
+                self.sender.send(Message::StatusNotifierItem(sni));
+            }
+            return;
+        }
+
+        match call {
+            Call::Owner => match reply.read1::<&str>() {
+                Ok(owner) => item.owner = Some(owner.to_string()),
+                Err(err) => debug!("can't parse owner of {}: {}", id, err),
+            },
+            Call::Properties => {
+                let (sni, menu_path) = match parse_item(&id, reply) {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        debug!("can't parse properties for {}: {}", id, err);
+                        return;
+                    }
+                };
+                item.menu_path = menu_path.clone();
+
+                match menu_path {
+                    Some(menu_path) => {
+                        let result = dbus::Message::new_method_call(
+                            &item.bus_name,
+                            &menu_path,
+                            MENU_IFACE,
+                            "GetLayout",
+                        )
+                        .map(|msg| msg.append3(0i32, -1i32, Vec::<&str>::new()))
+                        .and_then(|msg| self.send_call(c, &id, item, msg, Call::Menu(sni)));
+                        if let Err(err) = result {
+                            debug!("can't fetch menu for {}: {}", id, err);
+                        }
+                    }
+                    None => self.sender.send(Message::StatusNotifierItem(sni)),
+                }
+            }
+            Call::Menu(mut sni) => {
+                sni.menu = parse_menu(reply).unwrap_or_else(|err| {
+                    debug!("can't parse menu for {}: {}", id, err);
+                    Vec::new()
+                });
+                self.sender.send(Message::StatusNotifierItem(sni));
+            }
+        }
+    }
+
+    /// Returns when refresh needs to be called again.
+    pub fn next_refresh(&self) -> Option<Instant> {
+        self.items
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|item| item.deadline())
+            .min()
+    }
+
+    /// Asks the items that have been registered or have changed since the
+    /// last call for their properties, and gives up on those that haven't
+    /// replied in time.
+    pub fn refresh(&self, c: &Connection) {
+        let now = Instant::now();
+        let mut items = self.items.lock().unwrap();
+
+        for (id, item) in items.iter_mut() {
+            match item.deadline() {
+                Some(deadline) if deadline <= now => (),
+                _ => continue,
+            }
+
+            if item.call_sent.is_some() {
+                debug!("{} didn't reply in time", id);
+                item.call_sent = None;
+                item.dirty = true;
+                self.forget_calls(id);
+                continue;
+            }
+
+            item.last_fetch = Some(now);
+            if item.owner.is_none() {
+                let result = dbus::Message::new_method_call(
+                    "org.freedesktop.DBus",
+                    "/org/freedesktop/DBus",
+                    "org.freedesktop.DBus",
+                    "GetNameOwner",
+                )
+                .map(|msg| msg.append1(&item.bus_name))
+                .and_then(|msg| self.send_call(c, id, item, msg, Call::Owner));
+                if let Err(err) = result {
+                    debug!("can't get the owner of {}: {}", id, err);
+                }
+                continue;
+            }
+
+            item.dirty = false;
+            let result = dbus::Message::new_method_call(
+                &item.bus_name,
+                &item.path,
+                PROPERTIES_IFACE,
+                "GetAll",
+            )
+            .map(|msg| msg.append1(ITEM_IFACE))
+            .and_then(|msg| self.send_call(c, id, item, msg, Call::Properties));
+            if let Err(err) = result {
+                debug!("can't fetch properties for {}: {}", id, err);
+            }
+        }
+    }
+
+    /// Returns the method call to be sent to the item the user has
+    /// interacted with on the Host.
+    pub fn activation_message(&self, act: StatusNotifierActivation) -> Option<dbus::Message> {
+        let items = self.items.lock().unwrap();
+        let item = items.get(&act.id)?;
+
+        let msg = match act.menu_item {
+            Some(menu_item) => {
+                let menu_path = item.menu_path.as_ref()?;
+                dbus::Message::new_method_call(&item.bus_name, menu_path, MENU_IFACE, "Event")
+                    .ok()?
+                    .append3(menu_item, "clicked", Variant(0i32))
+                    .append1(0u32)
+            }
+            None => {
+                dbus::Message::new_method_call(&item.bus_name, &item.path, ITEM_IFACE, "Activate")
+                    .ok()?
+                    .append2(0i32, 0i32)
+            }
+        };
+
+        Some(msg)
+    }
+}
+
+fn create_tree(watcher: Arc<StatusNotifierWatcher>) -> tree::Tree<tree::MTFn<()>, ()> {
+    let f = tree::Factory::new_fn::<()>();
+
+    let w = watcher.clone();
+    let register_item = f
+        .method("RegisterStatusNotifierItem", (), move |m| {
+            let service: &str = m.msg.read1()?;
+            let sender = m.msg.sender().map_or(String::new(), |s| s.to_string());
+
+            // Some implementations register with their object path, and
+            // others with their bus name, which may be a well-known one.
+            let (bus_name, owner, path) = if service.starts_with('/') {
+                (sender.as_str(), Some(sender.as_str()), service)
+            } else if service.starts_with(':') {
+                (service, Some(service), ITEM_DEFAULT_PATH)
+            } else {
+                (service, None, ITEM_DEFAULT_PATH)
+            };
+            let id = w
+                .register_item(bus_name, owner, path)
+                .map_err(|err| tree::MethodErr::failed(&err))?;
+            debug!("status notifier item registered: {}", id);
+
+            let signal = dbus::Message::signal(
+                m.path.get_name(),
+                &WATCHER_NAME.into(),
+                &"StatusNotifierItemRegistered".into(),
+            )
+            .append1(id);
+            Ok(vec![m.msg.method_return(), signal])
+        })
+        .inarg::<&str, _>("service");
+
+    // The Host acts as the only StatusNotifierHost, so there's nothing to
+    // do here.
+    let register_host = f
+        .method("RegisterStatusNotifierHost", (), |m| {
+            Ok(vec![m.msg.method_return()])
+        })
+        .inarg::<&str, _>("service");
+
+    let w = watcher.clone();
+    let registered_items = f
+        .property::<Vec<String>, _>("RegisteredStatusNotifierItems", ())
+        .on_get(move |i, _| {
+            i.append(w.registered_items());
+            Ok(())
+        });
+    let host_registered = f
+        .property::<bool, _>("IsStatusNotifierHostRegistered", ())
+        .on_get(|i, _| {
+            i.append(true);
+            Ok(())
+        });
+    let protocol_version = f.property::<i32, _>("ProtocolVersion", ()).on_get(|i, _| {
+        i.append(0i32);
+        Ok(())
+    });
+
+    let iface = f
+        .interface(WATCHER_NAME, ())
+        .add_m(register_item)
+        .add_m(register_host)
+        .add_p(registered_items)
+        .add_p(host_registered)
+        .add_p(protocol_version)
+        .add_s(
+            f.signal("StatusNotifierItemRegistered", ())
+                .sarg::<&str, _>("service"),
+        )
+        .add_s(
+            f.signal("StatusNotifierItemUnregistered", ())
+                .sarg::<&str, _>("service"),
+        )
+        .add_s(f.signal("StatusNotifierHostRegistered", ()));
+
+    f.tree(())
+        .add(f.object_path(WATCHER_PATH, ()).introspectable().add(iface))
+}
+
+/// Registers the StatusNotifierWatcher service on the bus, and subscribes
+/// to the signals needed to track the registered items.
+pub fn register(c: &Connection, watcher: Arc<StatusNotifierWatcher>) -> Result<(), String> {
+    c.add_match(&format!("type='signal',interface='{}'", ITEM_IFACE))
+        .map_err(|err| err.to_string())?;
+    c.add_match(&format!("type='signal',interface='{}'", MENU_IFACE))
+        .map_err(|err| err.to_string())?;
+    c.add_match(dbus_listener::NAME_OWNER_CHANGED_RULE)
+        .map_err(|err| err.to_string())?;
+    dbus_listener::serve(c, WATCHER_NAME, create_tree(watcher))?;
+
+    Ok(())
+}
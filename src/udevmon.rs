@@ -19,94 +19,120 @@
 //
 
 use std::io;
-use std::process::Command;
-use std::ptr;
-use std::thread;
-use std::time::Duration;
-
 use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
 
-use libc::{c_int, c_short, c_ulong, c_void};
-use shlex::split;
+use log::{debug, error};
 
-#[repr(C)]
-struct pollfd {
-    fd: c_int,
-    events: c_short,
-    revents: c_short,
-}
+use crate::reactor::Source;
 
-#[repr(C)]
-struct sigset_t {
-    __private: c_void,
-}
+// How long to wait before trying to listen again after an error.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
-#[allow(non_camel_case_types)]
-type nfds_t = c_ulong;
+/// Handler for the udev events of a particular subsystem.
+pub type Handler = Box<dyn FnMut(&udev::Event) + Send>;
 
-const POLLIN: c_short = 0x0001;
-
-extern "C" {
-    fn ppoll(
-        fds: *mut pollfd,
-        nfds: nfds_t,
-        timeout_ts: *mut libc::timespec,
-        sigmask: *const sigset_t,
-    ) -> c_int;
+/// Dispatches udev events to the handlers registered for their subsystem.
+/// Only the subsystems with a registered handler are monitored.
+pub struct UdevMonitor {
+    handlers: Vec<(String, Handler)>,
+    socket: Option<udev::MonitorSocket>,
+    retry_at: Option<Instant>,
 }
 
-pub fn monitor() -> io::Result<()> {
-    let context = udev::Context::new()?;
-    let monitor = udev::MonitorBuilder::new(&context)?;
-    let mut socket = monitor.listen()?;
-    let mut fds = vec![pollfd {
-        fd: socket.as_raw_fd(),
-        events: POLLIN,
-        revents: 0,
-    }];
-
-    loop {
-        let result = unsafe {
-            ppoll(
-                (&mut fds[..]).as_mut_ptr(),
-                fds.len() as nfds_t,
-                ptr::null_mut(),
-                ptr::null(),
-            )
-        };
-
-        if result < 0 {
-            return Err(io::Error::last_os_error());
+impl UdevMonitor {
+    pub fn new() -> UdevMonitor {
+        UdevMonitor {
+            handlers: Vec::new(),
+            socket: None,
+            retry_at: None,
         }
+    }
 
-        let event = match socket.next() {
-            Some(evt) => evt,
-            None => {
-                thread::sleep(Duration::from_millis(10));
-                continue;
-            }
-        };
-
-        if event.sysname().to_str().unwrap_or("") == "card0" {
-            let argsline = "--output Virtual-1 --auto";
-            let args = split(&argsline).unwrap();
+    pub fn add_handler(&mut self, subsystem: &str, handler: Handler) {
+        self.handlers.push((subsystem.to_string(), handler));
+    }
 
-            let exit_status = Command::new("xrandr").args(args).status().unwrap();
-            let exit_code = match exit_status.code() {
-                Some(code) => code,
-                None => -1,
-            };
-            println!("xrandr exit code: {}", exit_code);
-        }
+    fn dispatch(&mut self, event: &udev::Event) {
+        let subsystem = event.subsystem().map_or("", |s| s.to_str().unwrap_or(""));
 
-        println!(
+        debug!(
             "{}: {} {} (subsystem={}, sysname={}, devtype={})",
             event.sequence_number(),
             event.event_type(),
             event.syspath().to_str().unwrap_or("---"),
-            event.subsystem().map_or("", |s| s.to_str().unwrap_or("")),
+            subsystem,
             event.sysname().to_str().unwrap_or(""),
             event.devtype().map_or("", |s| s.to_str().unwrap_or(""))
         );
+
+        for (_, handler) in self.handlers.iter_mut().filter(|(s, _)| s == subsystem) {
+            handler(event);
+        }
+    }
+
+    fn listen(&self) -> io::Result<udev::MonitorSocket> {
+        let context = udev::Context::new()?;
+        let mut monitor = udev::MonitorBuilder::new(&context)?;
+        for (subsystem, _) in self.handlers.iter() {
+            monitor.match_subsystem(subsystem)?;
+        }
+        monitor.listen()
+    }
+
+    /// Starts listening for events. If that's not possible, it will be
+    /// retried later from the reactor.
+    pub fn start(&mut self) {
+        match self.listen() {
+            Ok(socket) => {
+                self.socket = Some(socket);
+                self.retry_at = None;
+            }
+            Err(err) => {
+                error!("can't listen for udev events: {}", err.to_string());
+                self.socket = None;
+                self.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+impl Source for UdevMonitor {
+    fn fds(&self) -> Vec<libc::pollfd> {
+        match &self.socket {
+            Some(socket) => vec![libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }],
+            None => Vec::new(),
+        }
+    }
+
+    fn handle(&mut self, pfd: &libc::pollfd) -> Result<(), String> {
+        if pfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+            error!("udev monitor socket failed, listening again");
+            self.start();
+            return Ok(());
+        }
+
+        // The socket is non-blocking, so this drains the pending events.
+        let events: Vec<udev::Event> = match self.socket.as_mut() {
+            Some(socket) => socket.collect(),
+            None => return Ok(()),
+        };
+        for event in events.iter() {
+            self.dispatch(event);
+        }
+        Ok(())
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    fn handle_deadline(&mut self) -> Result<(), String> {
+        self.start();
+        Ok(())
     }
 }